@@ -0,0 +1,42 @@
+// Max decimal precision of a BigQuery Numeric type
+const MAX_BQ_DECIMALS: usize = 9;
+
+pub const SOL_DECIMALS: u8 = 9;
+
+// trim amount to max decimal precision
+// allows in a BigQuery Decimal type
+pub fn trim_decimals(amount: &String) -> &str {
+    let parts: Vec<&str> = amount.split('.').collect();
+
+    //any decimals at all?
+    if parts.len() < 2 {
+        return &amount[..];
+    }
+
+    //less than the max decimals?
+    let decimals = parts[1].len();
+    if decimals <= MAX_BQ_DECIMALS {
+        return &amount[..];
+    }
+
+    //return the amount with the
+    //the full integer part, the period, and the max decimals
+    let max_length = parts[0].len() + 1 + MAX_BQ_DECIMALS;
+    return &amount[..max_length];
+}
+
+// format a raw amount in base units (e.g. lamports)
+// as a decimal string in UI units
+pub fn format_amount(raw_amount: i128, decimals: u8) -> String {
+    let sign = if raw_amount < 0 { "-" } else { "" };
+    let digits = raw_amount.abs().to_string();
+    let decimals = decimals as usize;
+    if decimals == 0 {
+        return format!("{}{}", sign, digits);
+    }
+
+    let padded = format!("{:0>width$}", digits, width = decimals + 1);
+    let (integer, fraction) = padded.split_at(padded.len() - decimals);
+    let amount = format!("{}{}.{}", sign, integer, fraction);
+    return trim_decimals(&amount).to_owned();
+}
@@ -0,0 +1,202 @@
+use std::{
+    fs,
+    path::{
+        Path,
+        PathBuf,
+    },
+    sync::Arc,
+    thread,
+    time::Duration,
+};
+
+use serde::{
+    Deserialize,
+    Serialize,
+};
+use solana_sdk::clock::Slot;
+use tracing::{
+    info,
+    info_span,
+};
+
+use crate::{
+    block_listener::Listener,
+    config::Config,
+    lease::{
+        self,
+        Heartbeat,
+        Lease,
+        LeaseStore,
+    },
+};
+
+// Lease through which the progress of a claimed range is saved
+struct LeaseHandle {
+    store: Arc<dyn LeaseStore>,
+    owner: String,
+    ttl: Duration,
+    heartbeat: Heartbeat,
+}
+
+/// Progress of a shard of a backfill, saved to its own file once the
+/// rows of a range of slots are written, or to the lease of the range
+/// when workers coordinate through leases.
+#[derive(Deserialize, Serialize)]
+pub struct ShardProgress {
+    pub first_slot: Slot,
+    pub last_slot: Slot,
+    //Latest slot whose rows are written, None before the first range
+    processed_slot: Option<Slot>,
+    #[serde(skip)]
+    path: PathBuf,
+    #[serde(skip)]
+    lease: Option<LeaseHandle>,
+}
+
+impl ShardProgress {
+    /// Read the progress of the shard, or start it if it has none.
+    pub fn load(progress_dir: &str, first_slot: Slot, last_slot: Slot) -> Result<ShardProgress, String> {
+        let path = Path::new(progress_dir).join(format!("shard-{}-{}.json", first_slot, last_slot));
+        if !path.exists() {
+            return Ok(ShardProgress {
+                first_slot: first_slot,
+                last_slot: last_slot,
+                processed_slot: None,
+                path: path,
+                lease: None,
+            });
+        }
+        let contents = fs::read_to_string(&path)
+            .map_err(|err| format!("Failed to read {}: {}", path.display(), err))?;
+        let mut progress: ShardProgress = serde_json::from_str(&contents)
+            .map_err(|err| format!("Failed to parse {}: {}", path.display(), err))?;
+        progress.path = path;
+        return Ok(progress);
+    }
+
+    /// Progress of a range claimed by the owner of the lease.
+    /// The lease is renewed in the background while the progress is held.
+    pub fn from_lease(lease: Lease, store: Arc<dyn LeaseStore>, owner: &str, ttl: Duration) -> ShardProgress {
+        let heartbeat = Heartbeat::start(
+            Arc::clone(&store),
+            lease.first_slot,
+            lease.last_slot,
+            owner,
+            ttl,
+        );
+        ShardProgress {
+            first_slot: lease.first_slot,
+            last_slot: lease.last_slot,
+            processed_slot: lease.processed_slot,
+            path: PathBuf::new(),
+            lease: Some(LeaseHandle {
+                store: store,
+                owner: owner.to_string(),
+                ttl: ttl,
+                heartbeat: heartbeat,
+            }),
+        }
+    }
+
+    /// Slot after which the shard resumes.
+    pub fn processed_slot(&self) -> Slot {
+        self.processed_slot.unwrap_or(self.first_slot - 1)
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.processed_slot() >= self.last_slot
+    }
+
+    /// False once another worker took the lease of the range over.
+    pub fn is_owned(&self) -> bool {
+        match &self.lease {
+            Some(lease) => lease.heartbeat.is_owned(),
+            None => true,
+        }
+    }
+
+    /// Returns false if the lease of the range was taken over by another worker.
+    pub fn save(&mut self, processed_slot: Slot) -> Result<bool, String> {
+        self.processed_slot = Some(processed_slot);
+        if let Some(lease) = &self.lease {
+            if !lease.heartbeat.is_owned() {
+                return Ok(false);
+            }
+            //Saving the progress renews the lease
+            return lease.store.renew(
+                self.first_slot,
+                self.last_slot,
+                &lease.owner,
+                lease.ttl,
+                Some(processed_slot),
+            );
+        }
+        lease::write_json(&self.path, &*self)?;
+        return Ok(true);
+    }
+}
+
+/// Split the slot range in shards of about the same size.
+pub fn shard_ranges(start_slot: Slot, end_slot: Slot, shard_count: u64) -> Vec<(Slot, Slot)> {
+    let slot_count = end_slot - start_slot + 1;
+    let shard_count = shard_count.min(slot_count).max(1);
+    let shard_size = (slot_count + shard_count - 1) / shard_count;
+    let mut ranges: Vec<(Slot, Slot)> = Vec::new();
+    let mut first_slot = start_slot;
+    while first_slot <= end_slot {
+        let last_slot = (first_slot + shard_size - 1).min(end_slot);
+        ranges.push((first_slot, last_slot));
+        first_slot = last_slot + 1;
+    }
+    return ranges;
+}
+
+/// Ingest the slots from start to end, both included, with one listener
+/// per shard running concurrently. Shards resume from their saved
+/// progress, so the same command can be run again after a restart.
+/// When a lease store is set the shards are replaced by leased ranges,
+/// shared with the other instances running the same backfill.
+pub fn run(config: &Config) -> Result<(), String> {
+    let start_slot = config.start_slot
+        .ok_or_else(|| "Start slot is required by the backfill".to_string())?;
+    let end_slot = config.end_slot
+        .ok_or_else(|| "End slot is required by the backfill".to_string())?;
+    if config.lease.dir.is_some() {
+        let mut lease_config = config.clone();
+        lease_config.slots_behind_latest = 0;
+        return lease::run(&lease_config);
+    }
+    let progress_dir = &config.backfill.progress_dir;
+    fs::create_dir_all(progress_dir)
+        .map_err(|err| format!("Failed to create {}: {}", progress_dir, err))?;
+
+    let ranges = shard_ranges(start_slot, end_slot, config.backfill.shards);
+    //The processors and getBlock requests are shared out between the shards
+    let mut shard_config = config.clone();
+    shard_config.max_processor_count = (config.max_processor_count / ranges.len()).max(1);
+    shard_config.prefetch_window = (config.prefetch_window / ranges.len()).max(1);
+    //Historical slots are final, no need to trail the latest slot
+    shard_config.slots_behind_latest = 0;
+
+    let mut handles: Vec<thread::JoinHandle<Result<(), String>>> = Vec::new();
+    for (first_slot, last_slot) in ranges {
+        let progress = ShardProgress::load(progress_dir, first_slot, last_slot)?;
+        if progress.is_done() {
+            info!(first_slot, last_slot, "Shard is already done.");
+            continue;
+        }
+        let shard_config = shard_config.clone();
+        handles.push(thread::spawn(move || {
+            let span = info_span!("shard", first_slot, last_slot);
+            let _enter = span.enter();
+            let mut listener = Listener::for_shard(shard_config, progress)?;
+            listener.listen()?;
+            info!(first_slot, last_slot, "Shard done.");
+            Ok(())
+        }));
+    }
+    for handle in handles {
+        handle.join().map_err(|_| "Shard panicked".to_string())??;
+    }
+    return Ok(());
+}
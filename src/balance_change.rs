@@ -0,0 +1,109 @@
+use std::collections::BTreeMap;
+
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use solana_sdk::{
+    clock::Slot,
+    transaction::Transaction as SolanaTransaction,
+};
+use solana_transaction_status::{
+    UiTransactionStatusMeta,
+    UiTransactionTokenBalance,
+};
+
+use crate::{
+    amount::{
+        self,
+        SOL_DECIMALS,
+    },
+    bigquery_record,
+    schema::Numeric,
+};
+
+bigquery_record! {
+    /// Change of the SOL or token balance of an account in a transaction.
+    /// Amounts are in UI units, e.g. SOL instead of lamports.
+    #[derive(Serialize)]
+    pub struct BalanceChange {
+        block_timestamp: Option<DateTime<Utc>>,
+        slot: u64,
+        transaction_id: String,
+        account: String,
+        //None for SOL balances
+        mint: Option<String>,
+        pre_balance: Numeric,
+        post_balance: Numeric,
+        delta: Numeric,
+    }
+}
+
+
+//Raw pre and post balances and decimals, by account index and mint
+type TokenBalances = BTreeMap<(u8, String), (i128, i128, u8)>;
+
+fn token_balances(meta: &UiTransactionStatusMeta) -> TokenBalances {
+    let mut balances = TokenBalances::new();
+    let empty: Vec<UiTransactionTokenBalance> = Vec::new();
+    let pre_balances = meta.pre_token_balances.as_ref().unwrap_or(&empty);
+    let post_balances = meta.post_token_balances.as_ref().unwrap_or(&empty);
+
+    for balance in pre_balances {
+        let raw_amount = balance.ui_token_amount.amount.parse().unwrap_or(0);
+        let entry = balances
+            .entry((balance.account_index, balance.mint.clone()))
+            .or_insert((0, 0, balance.ui_token_amount.decimals));
+        entry.0 = raw_amount;
+    }
+    for balance in post_balances {
+        let raw_amount = balance.ui_token_amount.amount.parse().unwrap_or(0);
+        let entry = balances
+            .entry((balance.account_index, balance.mint.clone()))
+            .or_insert((0, 0, balance.ui_token_amount.decimals));
+        entry.1 = raw_amount;
+    }
+    return balances;
+}
+
+impl BalanceChange {
+    /// One balance change per (account, mint) whose balance changed
+    /// in the transaction. SOL balances have no mint.
+    pub fn from_transaction(
+        block_timestamp: &Option<DateTime<Utc>>,
+        slot: Slot,
+        meta: &UiTransactionStatusMeta,
+        solana_transaction: &SolanaTransaction,
+    ) -> Vec<BalanceChange> {
+        let transaction_id = solana_transaction.signatures[0].to_string();
+        let account_keys = &solana_transaction.message.account_keys;
+        let mut balance_changes: Vec<BalanceChange> = Vec::new();
+        let mut push = |account_index: usize, mint: Option<String>, pre: i128, post: i128, decimals: u8| {
+            if pre == post {
+                return;
+            }
+            balance_changes.push(BalanceChange {
+                block_timestamp: *block_timestamp,
+                slot: slot,
+                transaction_id: transaction_id.clone(),
+                account: account_keys[account_index].to_string(),
+                mint: mint,
+                pre_balance: Numeric(amount::format_amount(pre, decimals)),
+                post_balance: Numeric(amount::format_amount(post, decimals)),
+                delta: Numeric(amount::format_amount(post - pre, decimals)),
+            });
+        };
+
+        for index in 0..account_keys.len() {
+            push(
+                index,
+                None,
+                meta.pre_balances[index] as i128,
+                meta.post_balances[index] as i128,
+                SOL_DECIMALS,
+            );
+        }
+        for ((account_index, mint), (pre, post, decimals)) in token_balances(meta) {
+            push(account_index as usize, Some(mint), pre, post, decimals);
+        }
+        return balance_changes;
+    }
+}
@@ -0,0 +1,306 @@
+use std::{
+    sync::mpsc::{
+        self,
+        Receiver,
+        RecvTimeoutError,
+        Sender,
+        SyncSender,
+    },
+    thread::{
+        self,
+        JoinHandle,
+    },
+    time::Instant,
+};
+
+use serde::Serialize;
+use tracing::{
+    debug,
+    info_span,
+};
+
+use crate::{
+    balance_change::BalanceChange,
+    bigquery::BigQuery,
+    block::Block,
+    concurrency::WritePace,
+    config::{
+        BatchConfig,
+        Config,
+    },
+    metrics::BATCH_FLUSHES,
+    slot_status::SlotStatus,
+    transaction::Transaction,
+    transfer::Transfer,
+    vote::Vote,
+};
+
+/// Rows produced by the processing of one block.
+pub struct BlockRows {
+    pub block: Block,
+    pub transactions: Vec<Transaction>,
+    pub transfers: Vec<Transfer>,
+    pub balance_changes: Vec<BalanceChange>,
+    pub votes: Vec<Vote>,
+    //Status of the slot of the block, when slots are recorded
+    pub slot: Option<SlotStatus>,
+}
+
+impl BlockRows {
+    pub fn new(block: Block) -> BlockRows {
+        BlockRows {
+            block: block,
+            transactions: Vec::new(),
+            transfers: Vec::new(),
+            balance_changes: Vec::new(),
+            votes: Vec::new(),
+            slot: None,
+        }
+    }
+
+    fn row_count(&self) -> usize {
+        1 + self.transactions.len()
+            + self.transfers.len()
+            + self.balance_changes.len()
+            + self.votes.len()
+            + self.slot.iter().count()
+    }
+}
+
+fn json_size<T: Serialize>(rows: &[T]) -> usize {
+    rows.iter()
+        .map(|row| serde_json::to_vec(row).map(|json| json.len()).unwrap_or(0))
+        .sum()
+}
+
+// Rows of a block with their estimated size, measured by the processor
+struct SizedRows {
+    rows: BlockRows,
+    row_count: usize,
+    byte_size: usize,
+}
+
+enum Message {
+    Rows(SizedRows),
+    //Status of every slot of a processed range, with the size of the rows
+    Slots(Vec<SlotStatus>, usize),
+    //Write the pending rows now and acknowledge once they are written,
+    //with whether every row since the last acknowledgement was written
+    Flush(Sender<bool>),
+}
+
+/// Hands the rows of processed blocks to the writer.
+#[derive(Clone)]
+pub struct BatchSender {
+    sender: SyncSender<Message>,
+}
+
+impl BatchSender {
+    /// Blocks while the writer is busy and its queue is full.
+    pub fn send(&self, rows: BlockRows) {
+        let byte_size = json_size(&[&rows.block])
+            + json_size(&rows.transactions)
+            + json_size(&rows.transfers)
+            + json_size(&rows.balance_changes)
+            + json_size(&rows.votes)
+            + rows.slot.as_ref().map(|slot| json_size(&[slot])).unwrap_or(0);
+        let sized_rows = SizedRows {
+            row_count: rows.row_count(),
+            byte_size: byte_size,
+            rows: rows,
+        };
+        self.sender
+            .send(Message::Rows(sized_rows))
+            .expect("Batch writer stopped");
+    }
+
+    pub fn send_slots(&self, slots: Vec<SlotStatus>) {
+        let byte_size = json_size(&slots);
+        self.sender
+            .send(Message::Slots(slots, byte_size))
+            .expect("Batch writer stopped");
+    }
+
+    /// Wait until every row sent so far is written. Returns false if
+    /// any row sent since the last flush was dropped by the writer.
+    pub fn flush(&self) -> bool {
+        let (ack_sender, ack_receiver) = mpsc::channel();
+        self.sender
+            .send(Message::Flush(ack_sender))
+            .expect("Batch writer stopped");
+        return ack_receiver
+            .recv()
+            .expect("Batch writer stopped");
+    }
+}
+
+/// Accumulates the rows of many blocks and writes them with a single
+/// client once the pending rows reach the size or count thresholds,
+/// or when the flush interval has elapsed.
+pub struct BatchWriter {
+    sender: BatchSender,
+    handle: JoinHandle<()>,
+}
+
+struct Batch {
+    bq_client: BigQuery,
+    pace: WritePace,
+    row_count: usize,
+    byte_size: usize,
+    last_flush: Instant,
+    //Set when rows were dropped since the last acknowledged flush
+    is_failed: bool,
+}
+
+impl Batch {
+    fn add(&mut self, sized_rows: SizedRows) {
+        let rows = sized_rows.rows;
+        //Every block is written, even when the filters leave none of its
+        //transactions, since the listener resumes after the latest block
+        self.bq_client.add_block(rows.block);
+        if !rows.transactions.is_empty() {
+            self.bq_client.add_transactions(rows.transactions);
+        }
+        if !rows.transfers.is_empty() {
+            self.bq_client.add_transfers(rows.transfers);
+        }
+        if !rows.balance_changes.is_empty() {
+            self.bq_client.add_balance_changes(rows.balance_changes);
+        }
+        if !rows.votes.is_empty() {
+            self.bq_client.add_votes(rows.votes);
+        }
+        if let Some(slot) = rows.slot {
+            self.bq_client.add_slots(vec![slot]);
+        }
+        self.row_count += sized_rows.row_count;
+        self.byte_size += sized_rows.byte_size;
+    }
+
+    fn add_slots(&mut self, slots: Vec<SlotStatus>, byte_size: usize) {
+        self.row_count += slots.len();
+        self.byte_size += byte_size;
+        self.bq_client.add_slots(slots);
+    }
+
+    // Write the pending rows once any threshold is reached
+    fn flush_if_due(&mut self, config: &BatchConfig) {
+        if self.row_count >= config.max_pending_rows {
+            self.flush("rows");
+        } else if self.byte_size >= config.max_pending_bytes {
+            self.flush("bytes");
+        } else if self.last_flush.elapsed() >= config.flush_interval() {
+            self.flush("interval");
+        }
+    }
+
+    fn flush(&mut self, reason: &str) {
+        if self.row_count > 0 {
+            debug!(
+                reason,
+                rows = self.row_count,
+                bytes = self.byte_size,
+                "Flush batch."
+            );
+            BATCH_FLUSHES.with_label_values(&[reason]).inc();
+            let start = Instant::now();
+            if !self.bq_client.commit() {
+                self.is_failed = true;
+            }
+            self.pace.record(start.elapsed(), self.bq_client.take_failed_attempts());
+            //Rows queue up meanwhile, which slows down the processors
+            let delay = self.pace.delay();
+            if delay.as_millis() > 0 {
+                thread::sleep(delay);
+            }
+        }
+        self.row_count = 0;
+        self.byte_size = 0;
+        self.last_flush = Instant::now();
+    }
+}
+
+fn run(config: &Config, bq_client: BigQuery, receiver: Receiver<Message>) {
+    let span = info_span!("batch_writer");
+    let _enter = span.enter();
+    let flush_interval = config.batch.flush_interval();
+    let mut batch = Batch {
+        bq_client: bq_client,
+        pace: WritePace::new(config),
+        row_count: 0,
+        byte_size: 0,
+        last_flush: Instant::now(),
+        is_failed: false,
+    };
+    loop {
+        let wait = flush_interval
+            .checked_sub(batch.last_flush.elapsed())
+            .unwrap_or_default();
+        match receiver.recv_timeout(wait) {
+            Ok(Message::Flush(ack_sender)) => {
+                batch.flush("request");
+                let is_written = !batch.is_failed;
+                batch.is_failed = false;
+                //The sender may have stopped waiting
+                let _ = ack_sender.send(is_written);
+            }
+            Ok(Message::Rows(sized_rows)) => {
+                batch.add(sized_rows);
+                batch.flush_if_due(&config.batch);
+            }
+            Ok(Message::Slots(slots, byte_size)) => {
+                batch.add_slots(slots, byte_size);
+                batch.flush_if_due(&config.batch);
+            }
+            Err(RecvTimeoutError::Timeout) => {
+                batch.flush("interval");
+            }
+            Err(RecvTimeoutError::Disconnected) => {
+                batch.flush("shutdown");
+                return;
+            }
+        }
+    }
+}
+
+impl BatchWriter {
+    /// The writes are paced by their latency and errors.
+    /// Fails if the sink client cannot be created.
+    pub fn new(config: &Config) -> Result<BatchWriter, String> {
+        //Processors wait once this many blocks are queued
+        let (sender, receiver) = mpsc::sync_channel(config.max_processor_count);
+        let (ready_sender, ready_receiver) = mpsc::channel();
+        let config = config.clone();
+        //The client is created by the thread that uses it
+        let handle = thread::spawn(move || {
+            match BigQuery::new(&config) {
+                Ok(bq_client) => {
+                    let _ = ready_sender.send(Ok(()));
+                    run(&config, bq_client, receiver);
+                }
+                Err(err) => {
+                    let _ = ready_sender.send(Err(err));
+                }
+            }
+        });
+        ready_receiver
+            .recv()
+            .map_err(|_| "Batch writer stopped before it was ready".to_string())??;
+        return Ok(BatchWriter {
+            sender: BatchSender {
+                sender: sender,
+            },
+            handle: handle,
+        });
+    }
+
+    pub fn sender(&self) -> BatchSender {
+        self.sender.clone()
+    }
+
+    /// Write the pending rows and stop. Every sender must be dropped first.
+    pub fn close(self) {
+        drop(self.sender);
+        self.handle.join().expect("Batch writer panicked");
+    }
+}
@@ -2,38 +2,130 @@ use gcp_bigquery_client::error::BQError;
 use gcp_bigquery_client::model::query_request::QueryRequest;
 use gcp_bigquery_client::model::query_response::ResultSet;
 use gcp_bigquery_client::model::table_data_insert_all_request::TableDataInsertAllRequest;
+use serde::{
+    Deserialize,
+    Serialize,
+};
 use std::{
     env,
     io::{
         Error,
         ErrorKind
     },
+    path::{
+        Path,
+        PathBuf,
+    },
+    str::FromStr,
+    sync::atomic::{
+        AtomicUsize,
+        Ordering,
+    },
     thread,
     time::Duration,
 };
 use tokio::runtime::Runtime;
 use tokio::time::timeout;
+use tracing::{
+    error,
+    info,
+    warn,
+};
 
+use crate::balance_change::BalanceChange;
 use crate::transaction::Transaction;
+use crate::transfer::Transfer;
 use crate::block::Block;
+use crate::config::{
+    BatchConfig,
+    Config,
+    LoadJobConfig,
+    RetryConfig,
+    SinkConfig,
+};
+use crate::health::HEALTH;
+use crate::load_job::{
+    self,
+    LoadJobs,
+    StagedChunk,
+};
+use crate::metrics::{
+    BLOCKS_INGESTED,
+    INSERT_FAILURES,
+    INSERT_RETRIES,
+    TRANSACTIONS_INGESTED,
+};
+use crate::schema::{
+    Record,
+    BALANCE_CHANGES_TABLE_ID,
+    BLOCKS_TABLE_ID,
+    EPOCHS_TABLE_ID,
+    SLOTS_TABLE_ID,
+    TRANSACTIONS_TABLE_ID,
+    TRANSFERS_TABLE_ID,
+    VOTES_TABLE_ID,
+};
+use crate::slot_status::SlotStatus;
+use crate::storage_write::{
+    PendingWrite,
+    StorageWriter,
+};
+use crate::vote::Vote;
 
-const TRANSACTIONS_TABLE_ID: &str = "transactions";
-const BLOCKS_TABLE_ID: &str = "blocks";
+/// API used to write the rows.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum SinkBackend {
+    //Streaming inserts with tabledata.insertAll
+    InsertAll,
+    //Storage Write API, cheaper and with larger requests
+    StorageWrite,
+    //Load jobs of staged files, one per batch, for backfills
+    LoadJob,
+}
+
+impl FromStr for SinkBackend {
+    type Err = String;
+
+    fn from_str(backend: &str) -> Result<SinkBackend, String> {
+        match backend {
+            "insert_all" => Ok(SinkBackend::InsertAll),
+            "storage_write" => Ok(SinkBackend::StorageWrite),
+            "load_job" => Ok(SinkBackend::LoadJob),
+            _ => Err(format!("Unknown sink backend {}", backend)),
+        }
+    }
+}
 
 pub struct BigQuery {
     client: gcp_bigquery_client::Client,
+    //Set when rows are written with the Storage Write API
+    storage_writer: Option<StorageWriter>,
+    //Set when rows are written with load jobs
+    load_jobs: Option<LoadJobs>,
+    load_job_config: LoadJobConfig,
     runtime: Runtime,
     project_id: String,
     dataset_id: String,
-    block_pending: Option<Block>,
+    retry: RetryConfig,
+    batch: BatchConfig,
+    blocks_pending: Vec<Block>,
     transactions_pending: Vec<Transaction>,
+    transfers_pending: Vec<Transfer>,
+    balance_changes_pending: Vec<BalanceChange>,
+    votes_pending: Vec<Vote>,
+    slots_pending: Vec<SlotStatus>,
+    //Attempts to write that failed since the last commit
+    failed_attempts: AtomicUsize,
 }
 
 impl BigQuery {
     async fn get_client() -> gcp_bigquery_client::Client {
         let gcp_key = env::var("GOOGLE_APPLICATION_CREDENTIALS")
             .expect("Environment variable GOOGLE_APPLICATION_CREDENTIALS is required");
+        let mut attempt: u32 = 0;
         loop {
+            attempt += 1;
             let client_res = timeout(
                     Duration::from_secs(60),
                     gcp_bigquery_client::Client::from_service_account_key_file(&gcp_key)
@@ -41,38 +133,101 @@ impl BigQuery {
                 .await;
             match client_res {
                 Ok(client) => {
+                    HEALTH.set_sink_reachable(true);
                     return client;
                 }
                 Err(_) => {
-                    println!("Timed out waiting for the BigQuery client. Retry.");
+                    HEALTH.set_sink_reachable(false);
+                    warn!(attempt, "Timed out waiting for the BigQuery client. Retry.");
                 }
             }
         }
     }
 
-    pub fn new(project_id: &str, dataset_id: &str) -> BigQuery {
+    async fn get_storage_writer(project_id: &str, dataset_id: &str, sink: &SinkConfig) -> StorageWriter {
+        let gcp_key = env::var("GOOGLE_APPLICATION_CREDENTIALS")
+            .expect("Environment variable GOOGLE_APPLICATION_CREDENTIALS is required");
+        let mut attempt: u32 = 0;
+        loop {
+            attempt += 1;
+            let writer_res = timeout(
+                    Duration::from_secs(60),
+                    StorageWriter::new(&gcp_key, project_id, dataset_id, sink.stream_type)
+                )
+                .await;
+            match writer_res {
+                Ok(Ok(writer)) => {
+                    HEALTH.set_sink_reachable(true);
+                    return writer;
+                }
+                Ok(Err(err)) => {
+                    HEALTH.set_sink_reachable(false);
+                    warn!(attempt, error = %err, "Failed to create the Storage Write client. Retry.");
+                    tokio::time::sleep(Duration::from_secs(1)).await;
+                }
+                Err(_) => {
+                    HEALTH.set_sink_reachable(false);
+                    warn!(attempt, "Timed out waiting for the Storage Write client. Retry.");
+                }
+            }
+        }
+    }
+
+    pub fn new(config: &Config) -> Result<BigQuery, String> {
         let runtime = Runtime::new().unwrap();
         let client = runtime.block_on(Self::get_client());
+        let mut storage_writer: Option<StorageWriter> = None;
+        if config.sink.backend == SinkBackend::StorageWrite {
+            storage_writer = Some(runtime.block_on(
+                Self::get_storage_writer(&config.project_id, &config.dataset_id, &config.sink)));
+        }
+        let mut load_jobs: Option<LoadJobs> = None;
+        if config.sink.backend == SinkBackend::LoadJob {
+            let gcp_key = env::var("GOOGLE_APPLICATION_CREDENTIALS")
+                .map_err(|_| "Environment variable GOOGLE_APPLICATION_CREDENTIALS is required".to_string())?;
+            let jobs = runtime.block_on(LoadJobs::new(&gcp_key, config))
+                .map_err(|err| format!("Failed to create the load job client: {}", err))?;
+            load_jobs = Some(jobs);
+        }
 
-        BigQuery {
+        return Ok(BigQuery {
             client: client,
+            storage_writer: storage_writer,
+            load_jobs: load_jobs,
+            load_job_config: config.load_job.clone(),
             runtime: runtime,
-            project_id: project_id.to_string(),
-            dataset_id: dataset_id.to_string(),
-            block_pending: None,
+            project_id: config.project_id.clone(),
+            dataset_id: config.dataset_id.clone(),
+            retry: config.retry.clone(),
+            batch: config.batch.clone(),
+            blocks_pending: Vec::new(),
             transactions_pending: Vec::new(),
+            transfers_pending: Vec::new(),
+            balance_changes_pending: Vec::new(),
+            votes_pending: Vec::new(),
+            slots_pending: Vec::new(),
+            failed_attempts: AtomicUsize::new(0),
+        });
+    }
+
+    /// Load the chunks left by a previous run. Must be called once at startup,
+    /// before any listener stages new chunks, since blocks are loaded in order.
+    pub fn load_staged_chunks(&self) -> Result<(), String> {
+        load_job::remove_partial_chunks(&self.load_job_config.staging_dir)?;
+        for chunk in load_job::staged_chunks(&self.load_job_config.staging_dir) {
+            info!(chunk = %chunk.display(), "Load chunk staged by a previous run.");
+            if !self.load_chunk(&chunk) {
+                return Err(format!("Failed to load the staged chunk {}", chunk.display()));
+            }
         }
+        return Ok(());
     }
 
-    async fn query_latest_slot(&self) -> ResultSet {
+    async fn query(&self, sql: String) -> ResultSet {
         loop {
-            let query = QueryRequest::new(
-                format!("SELECT MAX(slot) AS slot FROM `{}.{}.{}`",
-                    self.project_id, self.dataset_id, BLOCKS_TABLE_ID
-                )
-            );
+            let query = QueryRequest::new(sql.clone());
             let res = timeout(
-                Duration::from_secs(60),
+                self.retry.timeout(),
                 self.client
                     .job()
                     .query(&self.project_id, query)
@@ -80,21 +235,29 @@ impl BigQuery {
             .await;
             match res {
                 Ok(set) => {
+                    HEALTH.set_sink_reachable(true);
                     return set.expect("Query failed");
                 }
                 Err(_) => {
-                    println!("Timed out waiting for the latest slot. Retry.");
+                    HEALTH.set_sink_reachable(false);
+                    warn!(query = sql.as_str(), "Timed out waiting for the query. Retry.");
                 }
             }
         }
     }
 
-    pub fn get_latest_slot(&self) -> Result<u64, BQError> {
-        let mut rows = self.runtime.block_on(self.query_latest_slot());
+    async fn query_max(&self, table_id: &str, column: &str) -> ResultSet {
+        return self.query(format!("SELECT MAX({}) AS {} FROM `{}.{}.{}`",
+            column, column, self.project_id, self.dataset_id, table_id
+        )).await;
+    }
+
+    fn get_max(&self, table_id: &str, column: &str) -> Result<u64, BQError> {
+        let mut rows = self.runtime.block_on(self.query_max(table_id, column));
         if rows.next_row() {
-            if let Some(slot) = rows.get_i64_by_name("slot")? {
-                if slot >= 0 {
-                    return Ok(slot as u64);
+            if let Some(value) = rows.get_i64_by_name(column)? {
+                if value >= 0 {
+                    return Ok(value as u64);
                 }
             }
         }
@@ -102,162 +265,426 @@ impl BigQuery {
             BQError::from(
                 Error::new(
                     ErrorKind::InvalidData,
-                    "Could not find latest slot",
+                    format!("Could not find latest {}", column),
+                )
+            )
+        );
+    }
+
+    pub fn get_latest_slot(&self) -> Result<u64, BQError> {
+        return self.get_max(BLOCKS_TABLE_ID, "slot");
+    }
+
+    /// Slot and hash of the latest stored block.
+    pub fn get_latest_block(&self) -> Result<(u64, String), BQError> {
+        let sql = format!(
+            "SELECT slot, blockhash FROM `{}.{}.{}` WHERE slot = (SELECT MAX(slot) FROM `{}.{}.{}`)",
+            self.project_id, self.dataset_id, BLOCKS_TABLE_ID,
+            self.project_id, self.dataset_id, BLOCKS_TABLE_ID,
+        );
+        let mut rows = self.runtime.block_on(self.query(sql));
+        if rows.next_row() {
+            let slot = rows.get_i64_by_name("slot")?;
+            let blockhash = rows.get_string_by_name("blockhash")?;
+            if let (Some(slot), Some(blockhash)) = (slot, blockhash) {
+                return Ok((slot as u64, blockhash));
+            }
+        }
+        return Err(
+            BQError::from(
+                Error::new(
+                    ErrorKind::InvalidData,
+                    "Could not find latest block",
                 )
             )
         );
     }
 
+    /// Latest epoch recorded by the epochs job.
+    pub fn get_latest_epoch(&self) -> Result<u64, BQError> {
+        return self.get_max(EPOCHS_TABLE_ID, "epoch");
+    }
+
+    /// Write rows to a table right away, outside of the pending rows.
+    pub fn insert<T: Serialize + Record>(&self, table_id: &str, rows: &[T]) -> bool {
+        if rows.is_empty() {
+            return true;
+        }
+        return self.runtime.block_on(self.insert_rows(table_id, rows, None));
+    }
+
+    /// Write rows to a table right away with streaming inserts, whatever the
+    /// backend, each with an insert ID so that the rows of a retry that were
+    /// already written are dropped by BigQuery.
+    pub fn insert_with_ids<T: Serialize + Record>(&self, table_id: &str, rows: &[T], insert_id: fn(&T) -> String) -> bool {
+        if rows.is_empty() {
+            return true;
+        }
+        return self.runtime.block_on(self.insert_rows(table_id, rows, Some(insert_id)));
+    }
+
     pub fn add_block(&mut self, block: Block) {
-        self.block_pending = Some(block);
+        self.blocks_pending.push(block);
+    }
+
+    pub fn add_transactions(&mut self, mut transactions: Vec<Transaction>) {
+        self.transactions_pending.append(&mut transactions);
     }
 
-    pub fn add_transaction(&mut self, transaction: Transaction) {
-        self.transactions_pending.push(transaction);
+    pub fn add_transfers(&mut self, mut transfers: Vec<Transfer>) {
+        self.transfers_pending.append(&mut transfers);
     }
 
-    fn create_transaction_request(&self)
-        -> Result<TableDataInsertAllRequest, BQError> {
-        let mut transactions = TableDataInsertAllRequest::new();
-        for transaction in &self.transactions_pending {
-            transactions.add_row(None, transaction)?;
+    pub fn add_balance_changes(&mut self, mut balance_changes: Vec<BalanceChange>) {
+        self.balance_changes_pending.append(&mut balance_changes);
+    }
+
+    pub fn add_votes(&mut self, mut votes: Vec<Vote>) {
+        self.votes_pending.append(&mut votes);
+    }
+
+    pub fn add_slots(&mut self, mut slots: Vec<SlotStatus>) {
+        self.slots_pending.append(&mut slots);
+    }
+
+    async fn insert_all<T: Serialize>(&self, table_id: &str, rows: &[T], insert_id: Option<fn(&T) -> String>)
+        -> Result<(), String> {
+        let mut request = TableDataInsertAllRequest::new();
+        for row in rows {
+            request.add_row(insert_id.map(|insert_id| insert_id(row)), row)
+                .map_err(|err| format!("Failed to add row: {:?}", err))?;
         }
-        Ok(transactions)
+
+        let res = self.client
+            .tabledata()
+            .insert_all(
+                &self.project_id,
+                &self.dataset_id,
+                table_id,
+                request
+            )
+            .await;
+        match res {
+            Err(err) => {
+                HEALTH.set_sink_reachable(false);
+                return Err(format!("Failed to insert rows: {:?}", err));
+            }
+            Ok(res) => {
+                HEALTH.set_sink_reachable(true);
+                if let Some(errors) = res.insert_errors {
+                    return Err(format!("One or more rows failed to insert: {:?}", errors));
+                }
+                return Ok(());
+            }
+        }
+    }
+
+    async fn write_rows(&self, writer: &StorageWriter, write: &mut PendingWrite) -> Result<(), String> {
+        let res = writer.write(write).await;
+        HEALTH.set_sink_reachable(res.is_ok());
+        return res;
+    }
+
+    // Split the rows in requests below the insertAll limits.
+    // The Storage Write API splits its own requests.
+    fn split_rows<'a, T: Serialize>(&self, table_id: &str, rows: &'a [T]) -> Vec<&'a [T]> {
+        let mut requests: Vec<&'a [T]> = Vec::new();
+        let mut start = 0;
+        let mut request_bytes = 0;
+        for (index, row) in rows.iter().enumerate() {
+            let row_bytes = serde_json::to_vec(row).map(|json| json.len()).unwrap_or(0);
+            if row_bytes > self.batch.max_request_bytes {
+                warn!(table = table_id, row_bytes, "Row is above the request size limit.");
+            }
+            let is_full = index - start >= self.batch.max_request_rows
+                || request_bytes + row_bytes > self.batch.max_request_bytes;
+            if index > start && is_full {
+                requests.push(&rows[start..index]);
+                start = index;
+                request_bytes = 0;
+            }
+            request_bytes += row_bytes;
+        }
+        if start < rows.len() {
+            requests.push(&rows[start..]);
+        }
+        return requests;
+    }
+
+    // Rows with insert IDs are written with streaming inserts, the only
+    // writes that BigQuery deduplicates by ID
+    async fn insert_rows<T: Serialize + Record>(&self, table_id: &str, rows: &[T], insert_id: Option<fn(&T) -> String>)
+        -> bool {
+        //The backend is chosen before the rows are split
+        if self.storage_writer.is_some() && insert_id.is_none() {
+            //Rows are encoded once and the write resumes where the last attempt stopped
+            match PendingWrite::new(table_id, rows) {
+                Ok(write) => {
+                    return self.insert_request(table_id, rows, Some(write), None).await;
+                }
+                Err(err) => {
+                    //Streaming inserts take the rows as JSON instead
+                    let (first_slot, last_slot) = slot_range(rows);
+                    error!(
+                        table = table_id,
+                        first_slot = ?first_slot,
+                        last_slot = ?last_slot,
+                        error = %err,
+                        "Failed to encode rows. Insert them with insertAll."
+                    );
+                }
+            }
+        }
+        let mut is_inserted = true;
+        for request_rows in self.split_rows(table_id, rows) {
+            if !self.insert_request(table_id, request_rows, None, insert_id).await {
+                is_inserted = false;
+            }
+        }
+        return is_inserted;
     }
 
-    async fn insert_transactions(&self) {
-        let retry_period = Duration::from_secs(1);
-        const MAX_ATTEMPTS: u32 = 10;
-        for attempt in 0..MAX_ATTEMPTS {
+    // Write the rows with the Storage Write API when they are encoded,
+    // otherwise with a single insertAll request
+    async fn insert_request<T: Serialize + Record>(
+        &self,
+        table_id: &str,
+        rows: &[T],
+        mut pending_write: Option<PendingWrite>,
+        insert_id: Option<fn(&T) -> String>) -> bool {
+
+        let retry_period = self.retry.retry_period();
+        let max_attempts = self.retry.max_attempts;
+        for attempt in 0..max_attempts {
             if attempt > 0 {
-                println!("Retry {}/{} after {} second(s).",
+                info!(
+                    table = table_id,
                     attempt,
-                    MAX_ATTEMPTS,
-                    retry_period.as_secs());
+                    max_attempts,
+                    retry_secs = retry_period.as_secs(),
+                    "Retry insert."
+                );
+                INSERT_RETRIES.with_label_values(&[table_id]).inc();
                 thread::sleep(retry_period);
             }
-            let transactions: TableDataInsertAllRequest;
-            match self.create_transaction_request() {
-                Ok(t) => {
-                    transactions = t;
+
+            let res = match (&self.storage_writer, &mut pending_write) {
+                (Some(writer), Some(write)) => {
+                    timeout(self.retry.timeout(), self.write_rows(writer, write)).await
                 }
-                Err(err) => {
-                    eprintln!("{:?}", err);
-                    eprintln!("Failed to add transaction row.");
-                    continue;
+                _ => {
+                    timeout(self.retry.timeout(), self.insert_all(table_id, rows, insert_id)).await
                 }
-            }
-            let res = timeout(
-                Duration::from_secs(60),
-                self.client
-                    .tabledata()
-                    .insert_all(
-                        &self.project_id,
-                        &self.dataset_id,
-                        TRANSACTIONS_TABLE_ID,
-                        transactions
-                    )
-            )
-            .await;
+            };
+
             match res {
                 Err(_) => {
-                    eprintln!("Timed out waiting to insert transactions.");
-                    continue;
+                    HEALTH.set_sink_reachable(false);
+                    self.failed_attempts.fetch_add(1, Ordering::Relaxed);
+                    warn!(table = table_id, attempt, "Timed out waiting to insert rows.");
                 }
-                Ok(r) => {
-                    match r {
-                        Err(err) => {
-                            eprintln!("{:?}", err);
-                            eprintln!("Failed to insert transactions.");
-                            continue;
-                        }
-                        Ok(res) => {
-                            if let Some(errors) = res.insert_errors {
-                                eprintln!("{:?}", errors);
-                                eprintln!("One or more transactions failed to insert.");
-                                continue;
-                            }
-                            return;
-                        }
-                    }
+                Ok(Err(err)) => {
+                    self.failed_attempts.fetch_add(1, Ordering::Relaxed);
+                    warn!(table = table_id, attempt, error = %err, "Failed to insert rows.");
+                }
+                Ok(Ok(())) => {
+                    return true;
                 }
             }
         }
+        let (first_slot, last_slot) = slot_range(rows);
+        error!(
+            table = table_id,
+            attempts = max_attempts,
+            first_slot = ?first_slot,
+            last_slot = ?last_slot,
+            "Gave up inserting rows."
+        );
+        INSERT_FAILURES.with_label_values(&[table_id]).inc();
+        if let Some(writer) = &self.storage_writer {
+            //The stream may be broken, the next write uses a new one
+            writer.forget_stream(table_id);
+        }
+        return false;
     }
 
-    async fn insert_block(&self) {
-        let retry_period = Duration::from_secs(1);
-        const MAX_ATTEMPTS: u32 = 10;
-        for attempt in 0..MAX_ATTEMPTS {
+    fn load_chunk(&self, chunk: &Path) -> bool {
+        let load_jobs = self.load_jobs
+            .as_ref()
+            .expect("Load jobs are not enabled");
+        let retry_period = self.retry.retry_period();
+        let max_attempts = self.retry.max_attempts;
+        for attempt in 0..max_attempts {
             if attempt > 0 {
-                println!("Retry {}/{} after {} second(s).",
+                info!(
+                    chunk = %chunk.display(),
                     attempt,
-                    MAX_ATTEMPTS,
-                    retry_period.as_secs());
+                    max_attempts,
+                    retry_secs = retry_period.as_secs(),
+                    "Retry load."
+                );
+                INSERT_RETRIES.with_label_values(&["load_job"]).inc();
                 thread::sleep(retry_period);
             }
-            let mut block_request = TableDataInsertAllRequest::new();
-            let block_pending = self.block_pending
-                .as_ref()
-                .expect("Failed to find block to insert");
-            if let Err(err) = block_request.add_row(None, block_pending) {
-                eprintln!("{:?}", err);
-                eprintln!("Failed to add block row.");
-                continue;
+            match self.runtime.block_on(load_jobs.load(chunk, attempt)) {
+                Ok(()) => {
+                    HEALTH.set_sink_reachable(true);
+                    return true;
+                }
+                Err(err) => {
+                    HEALTH.set_sink_reachable(false);
+                    self.failed_attempts.fetch_add(1, Ordering::Relaxed);
+                    warn!(chunk = %chunk.display(), attempt, error = %err, "Failed to load chunk.");
+                }
             }
+        }
+        //The chunk stays staged and is loaded again on the next start
+        error!(chunk = %chunk.display(), attempts = max_attempts, "Gave up loading chunk.");
+        INSERT_FAILURES.with_label_values(&["load_job"]).inc();
+        return false;
+    }
 
-            let res = timeout(
-                Duration::from_secs(60),
-                self.client
-                    .tabledata()
-                    .insert_all(
-                        &self.project_id,
-                        &self.dataset_id,
-                        BLOCKS_TABLE_ID,
-                        block_request
-                    )
-            )
-            .await;
+    fn stage_chunk(&self) -> Result<Option<PathBuf>, String> {
+        let slots: Vec<u64> = self.blocks_pending
+            .iter()
+            .map(|block| block.get_slot())
+            .chain(self.slots_pending.iter().map(|slot| slot.get_slot()))
+            .collect();
+        let first_slot = slots.iter().min().copied();
+        let last_slot = slots.iter().max().copied();
+        let chunk: StagedChunk;
+        match (first_slot, last_slot) {
+            (Some(first_slot), Some(last_slot)) => {
+                chunk = StagedChunk::create(&self.load_job_config.staging_dir, first_slot, last_slot)?;
+            }
+            _ => {
+                return Ok(None);
+            }
+        }
+        chunk.write(TRANSACTIONS_TABLE_ID, &self.transactions_pending)?;
+        chunk.write(VOTES_TABLE_ID, &self.votes_pending)?;
+        chunk.write(TRANSFERS_TABLE_ID, &self.transfers_pending)?;
+        chunk.write(BALANCE_CHANGES_TABLE_ID, &self.balance_changes_pending)?;
+        chunk.write(SLOTS_TABLE_ID, &self.slots_pending)?;
+        chunk.write(BLOCKS_TABLE_ID, &self.blocks_pending)?;
+        return chunk.finish().map(Some);
+    }
 
-            match res {
-                Err(_) => {
-                    eprintln!("Timed out waiting to insert block");
-                    continue
-                }
-                Ok(r) => {
-                    match r {
-                        Err(err) => {
-                            eprintln!("{:?}", err);
-                            eprintln!("Failed to insert block.");
-                            continue;
-                        }
-                        Ok(res) => {
-                            if let Some(errors) = res.insert_errors {
-                                eprintln!("{:?}", errors);
-                                eprintln!("Block failed to insert.");
-                                continue;
-                            }
-                            return;
-                        }
-                    }
+    // Stage the pending rows as a chunk of files and load it.
+    // Returns false if the rows were not loaded.
+    fn commit_load_job(&mut self) -> bool {
+        let mut is_loaded = true;
+        match self.stage_chunk() {
+            Ok(Some(chunk)) => {
+                is_loaded = self.load_chunk(&chunk);
+                if is_loaded {
+                    TRANSACTIONS_INGESTED.inc_by(self.transactions_pending.len() as u64);
+                    BLOCKS_INGESTED.inc_by(self.blocks_pending.len() as u64);
+                    info!(
+                        count = self.transactions_pending.len(),
+                        chunk = %chunk.display(),
+                        "Transactions loaded."
+                    );
                 }
             }
+            Ok(None) => {}
+            Err(err) => {
+                error!(error = %err, "Failed to stage chunk.");
+                INSERT_FAILURES.with_label_values(&["load_job"]).inc();
+                is_loaded = false;
+            }
         }
+        self.transactions_pending = Vec::new();
+        self.votes_pending = Vec::new();
+        self.transfers_pending = Vec::new();
+        self.balance_changes_pending = Vec::new();
+        self.slots_pending = Vec::new();
+        self.blocks_pending = Vec::new();
+        return is_loaded;
+    }
+
+    /// Number of failed attempts to write since the last call,
+    /// used to back off when the sink throttles.
+    pub fn take_failed_attempts(&self) -> usize {
+        self.failed_attempts.swap(0, Ordering::Relaxed)
     }
 
-    pub fn commit(mut self) {
-        if self.transactions_pending.len() == 0 {
-            return;
+    /// Write the pending rows of every table. Blocks are written last,
+    /// since the latest block is where the listener resumes.
+    /// Returns false if any row was dropped after all attempts.
+    pub fn commit(&mut self) -> bool {
+        if self.load_jobs.is_some() {
+            return self.commit_load_job();
+        }
+
+        let mut is_written = true;
+        if !self.transactions_pending.is_empty() {
+            let inserted = self.runtime.block_on(
+                self.insert_rows(TRANSACTIONS_TABLE_ID, &self.transactions_pending, None));
+            is_written &= inserted;
+            if inserted {
+                TRANSACTIONS_INGESTED.inc_by(self.transactions_pending.len() as u64);
+            }
+
+            info!(count = self.transactions_pending.len(), "Transactions recorded.");
+
+            self.transactions_pending = Vec::new();
         }
 
-        self.runtime.block_on(self.insert_transactions());
+        if !self.votes_pending.is_empty() {
+            is_written &= self.runtime.block_on(
+                self.insert_rows(VOTES_TABLE_ID, &self.votes_pending, None));
 
-        println!("Transactions recorded: {}", self.transactions_pending.len());
+            info!(count = self.votes_pending.len(), "Votes recorded.");
 
-        self.transactions_pending = Vec::new();
+            self.votes_pending = Vec::new();
+        }
+
+        if !self.transfers_pending.is_empty() {
+            is_written &= self.runtime.block_on(
+                self.insert_rows(TRANSFERS_TABLE_ID, &self.transfers_pending, None));
+            self.transfers_pending = Vec::new();
+        }
+
+        if !self.balance_changes_pending.is_empty() {
+            is_written &= self.runtime.block_on(
+                self.insert_rows(BALANCE_CHANGES_TABLE_ID, &self.balance_changes_pending, None));
+            self.balance_changes_pending = Vec::new();
+        }
 
-        self.runtime.block_on(self.insert_block());
+        if !self.slots_pending.is_empty() {
+            is_written &= self.runtime.block_on(
+                self.insert_rows(SLOTS_TABLE_ID, &self.slots_pending, None));
+            self.slots_pending = Vec::new();
+        }
 
-        self.block_pending = None;
+        if !self.blocks_pending.is_empty() {
+            if self.runtime.block_on(self.insert_rows(BLOCKS_TABLE_ID, &self.blocks_pending, None)) {
+                BLOCKS_INGESTED.inc_by(self.blocks_pending.len() as u64);
+            } else {
+                is_written = false;
+            }
+            self.blocks_pending = Vec::new();
+        }
+        return is_written;
     }
 }
+
+/// Load the chunks staged by a previous run of the load_job backend.
+/// Called once at startup, before the listeners start.
+pub fn recover_staged_chunks(config: &Config) -> Result<(), String> {
+    if config.sink.backend != SinkBackend::LoadJob {
+        return Ok(());
+    }
+    return BigQuery::new(config)?.load_staged_chunks();
+}
+
+// Lowest and highest slot of the rows, for the logs of failed inserts
+fn slot_range<T: Serialize>(rows: &[T]) -> (Option<u64>, Option<u64>) {
+    let slots: Vec<u64> = rows
+        .iter()
+        .filter_map(|row| serde_json::to_value(row).ok())
+        .filter_map(|value| value.get("slot").and_then(|slot| slot.as_u64()))
+        .collect();
+    return (slots.iter().min().cloned(), slots.iter().max().cloned());
+}
@@ -0,0 +1,90 @@
+use clap::{
+    Arg,
+    App,
+};
+use std::{
+    env,
+    process,
+};
+use solistener::{
+    backfill,
+    config::Config,
+    health,
+    logging,
+    server,
+};
+
+fn main() {
+    let matches = App::new("Backfill")
+        .version("0.1")
+        .author("Diego Wilson <diego.wilson.solis@gmail.com>")
+        .about("Ingest a range of historical slots with shards processed concurrently.")
+        .arg(Arg::with_name("config")
+            .long("config")
+            .short("c")
+            .env("SOLISTENER_CONFIG")
+            .value_name("FILE")
+            .help("TOML config file shared with solistener."))
+        .arg(Arg::with_name("start_slot")
+            .long("start-slot")
+            .short("s")
+            .required(true)
+            .value_name("SLOT")
+            .help("First slot of the range."))
+        .arg(Arg::with_name("end_slot")
+            .long("end-slot")
+            .short("e")
+            .required(true)
+            .value_name("SLOT")
+            .help("Last slot of the range."))
+        .arg(Arg::with_name("shards")
+            .long("shards")
+            .value_name("COUNT")
+            .help("Number of slot ranges processed concurrently."))
+        .arg(Arg::with_name("progress_dir")
+            .long("progress-dir")
+            .value_name("DIR")
+            .help("Directory of the progress files. Keep the same range and shards to resume."))
+        .get_matches();
+
+    let mut config = Config::load(matches.value_of("config"))
+        .expect("Failed to load the config");
+    let start_slot: u64 = matches.value_of("start_slot")
+        .unwrap()
+        .parse()
+        .expect("Start slot is not a valid number");
+    let end_slot: u64 = matches.value_of("end_slot")
+        .unwrap()
+        .parse()
+        .expect("End slot is not a valid number");
+    config.start_slot = Some(start_slot);
+    config.end_slot = Some(end_slot);
+    if let Some(shards) = matches.value_of("shards") {
+        config.backfill.shards = shards
+            .parse()
+            .expect("Shards is not a valid number");
+    }
+    if let Some(progress_dir) = matches.value_of("progress_dir") {
+        config.backfill.progress_dir = progress_dir.to_string();
+    }
+    if let Err(err) = config.validate() {
+        panic!("Config is not valid: {}", err);
+    }
+
+    logging::init(config.log_format);
+
+    env::var("GOOGLE_APPLICATION_CREDENTIALS")
+        .expect("Environment variable GOOGLE_APPLICATION_CREDENTIALS is required");
+
+    health::HEALTH.set_staleness_window(config.staleness_window());
+    server::serve(config.http_address().unwrap());
+
+    if let Err(err) = solistener::recover_staged_chunks(&config) {
+        eprintln!("Failed to recover the staged chunks: {}", err);
+        process::exit(1);
+    }
+    if let Err(err) = backfill::run(&config) {
+        eprintln!("{}", err);
+        process::exit(1);
+    }
+}
@@ -0,0 +1,57 @@
+use clap::{
+    Arg,
+    App,
+};
+use std::{
+    env,
+    process,
+};
+use solistener::{
+    config::Config,
+    epoch,
+    health,
+    logging,
+    server,
+};
+
+fn main() {
+    let matches = App::new("Epochs")
+        .version("0.1")
+        .author("Diego Wilson <diego.wilson.solis@gmail.com>")
+        .about("Record completed epochs, their inflation rate and the inflation rewards of the stake accounts.")
+        .arg(Arg::with_name("config")
+            .long("config")
+            .short("c")
+            .env("SOLISTENER_CONFIG")
+            .value_name("FILE")
+            .help("TOML config file shared with solistener."))
+        .arg(Arg::with_name("epoch")
+            .long("epoch")
+            .value_name("EPOCH")
+            .help("Record this completed epoch and exit. By default every epoch is recorded as it completes."))
+        .get_matches();
+
+    let config = Config::load(matches.value_of("config"))
+        .expect("Failed to load the config");
+    let epoch: Option<u64> = matches.value_of("epoch").map(|epoch| {
+        epoch
+            .parse()
+            .expect("Epoch is not a valid number")
+    });
+    if let Err(err) = config.validate() {
+        panic!("Config is not valid: {}", err);
+    }
+
+    logging::init(config.log_format);
+
+    env::var("GOOGLE_APPLICATION_CREDENTIALS")
+        .expect("Environment variable GOOGLE_APPLICATION_CREDENTIALS is required");
+
+    health::HEALTH.set_staleness_window(config.staleness_window());
+    server::serve(config.http_address().unwrap());
+
+    if let Err(err) = epoch::run(&config, epoch) {
+        eprintln!("{}", err);
+        process::exit(1);
+    }
+}
@@ -1,140 +1,322 @@
+use clap::{
+    Arg,
+    App,
+};
 use gcp_bigquery_client::error::BQError;
+use gcp_bigquery_client::model::clustering::Clustering;
+use gcp_bigquery_client::model::dataset::Dataset;
 use gcp_bigquery_client::model::table::Table;
-use gcp_bigquery_client::model::table_field_schema::TableFieldSchema;
 use gcp_bigquery_client::model::table_schema::TableSchema;
 use gcp_bigquery_client::model::time_partitioning::TimePartitioning;
-use std::env;
+use std::{
+    env,
+    process,
+};
+use solistener::{
+    config::Config,
+    schema::{
+        self,
+        TableDefinition,
+    },
+};
 
-const PROJECT_ID: &str = "datadragonio-stage";
-const DATASET_ID: &str = "solana_test";
-const BLOCK_TABLE_ID: &str = "blocks";
-const TRANSACTION_TABLE_ID: &str = "transactions";
+const NOT_FOUND: i64 = 404;
+
+fn is_not_found(err: &BQError) -> bool {
+    match err {
+        BQError::ResponseError { error } => error.error.code == NOT_FOUND,
+        _ => false,
+    }
+}
+
+// Create the dataset unless it already exists
+async fn ensure_dataset(
+    client: &gcp_bigquery_client::Client,
+    config: &Config,
+    dry_run: bool,
+) -> Result<Dataset, BQError> {
+    match client.dataset().get(&config.project_id, &config.dataset_id).await {
+        Ok(dataset) => {
+            println!("Dataset {} exists.", config.dataset_id);
+            return Ok(dataset);
+        }
+        Err(err) => {
+            if !is_not_found(&err) {
+                return Err(err);
+            }
+        }
+    }
+
+    let mut dataset = Dataset::new(&config.project_id, &config.dataset_id);
+    if let Some(location) = &config.tables.location {
+        dataset = dataset.location(location);
+    }
+    if !config.tables.labels.is_empty() {
+        dataset.labels = Some(config.tables.labels.clone());
+    }
+    if dry_run {
+        println!("Dataset {} is missing and would be created.", config.dataset_id);
+        return Ok(dataset);
+    }
+    let dataset = client.dataset().create(dataset).await?;
+    println!("Dataset created -> {:?}", dataset);
+    return Ok(dataset);
+}
+
+fn time_partitioning(config: &Config, definition: &TableDefinition) -> TimePartitioning {
+    let mut partitioning = TimePartitioning::new(config.tables.partitioning.name().to_string());
+    if let Some(field) = config.tables.partition_field(definition) {
+        partitioning = partitioning.field(field);
+    }
+    if let Some(expiration) = config.tables.partition_expiration() {
+        partitioning = partitioning.expiration_ms(expiration);
+    }
+    return partitioning;
+}
+
+fn clustering(config: &Config, definition: &TableDefinition) -> Option<Clustering> {
+    let fields = config.tables.clustering(definition);
+    if fields.is_empty() {
+        return None;
+    }
+    return Some(Clustering {
+        fields: Some(fields),
+    });
+}
+
+// Partitioning cannot be changed once the table exists, only reported
+fn check_partitioning(config: &Config, definition: &TableDefinition, table: &Table) {
+    let expected = time_partitioning(config, definition);
+    let is_same = match &table.time_partitioning {
+        Some(actual) => {
+            actual.r#type.eq_ignore_ascii_case(&expected.r#type)
+                && actual.field == expected.field
+        }
+        None => false,
+    };
+    if !is_same {
+        println!(
+            "Table {}: partitioning differs from the config and can only be changed by recreating the table.",
+            definition.table_id,
+        );
+    }
+}
+
+async fn create_table(
+    client: &gcp_bigquery_client::Client,
+    config: &Config,
+    dataset: &Dataset,
+    definition: &TableDefinition,
+) -> Result<(), BQError> {
+    let mut table = Table::from_dataset(
+            dataset,
+            definition.table_id,
+            TableSchema::new(definition.fields.clone()),
+        )
+        .friendly_name(definition.friendly_name)
+        .description(definition.description)
+        .time_partitioning(time_partitioning(config, definition));
+    if let Some(clustering) = clustering(config, definition) {
+        table = table.clustering(clustering);
+    }
+    for (key, value) in &config.tables.labels {
+        table = table.label(key, value);
+    }
+    let table = dataset.create_table(client, table).await?;
+    println!("Table created -> {:?}", table);
+    Ok(())
+}
+
+// Create the table if it is missing, otherwise add the missing columns.
+// Returns false if the table has columns that cannot be migrated.
+async fn migrate_table(
+    client: &gcp_bigquery_client::Client,
+    config: &Config,
+    dataset: &Dataset,
+    definition: &TableDefinition,
+    dry_run: bool,
+) -> Result<bool, BQError> {
+    let table_result = client
+        .table()
+        .get(&config.project_id, &config.dataset_id, definition.table_id, None)
+        .await;
+    let mut table: Table;
+    match table_result {
+        Ok(t) => {
+            table = t;
+        }
+        Err(err) => {
+            if !is_not_found(&err) {
+                return Err(err);
+            }
+            if dry_run {
+                println!("Table {} is missing and would be created.", definition.table_id);
+            } else {
+                create_table(client, config, dataset, definition).await?;
+            }
+            return Ok(true);
+        }
+    }
+
+    check_partitioning(config, definition, &table);
+
+    //Unlike the rest of the partitioning, the expiration can be updated in place
+    let expected_expiration = time_partitioning(config, definition).expiration_ms;
+    let actual_expiration = table.time_partitioning
+        .as_ref()
+        .and_then(|partitioning| partitioning.expiration_ms.clone());
+    let is_expiration_changed = table.time_partitioning.is_some() && expected_expiration != actual_expiration;
+    if is_expiration_changed {
+        println!(
+            "Table {}: partition expiration is {} ms but expected {} ms.",
+            definition.table_id,
+            actual_expiration.unwrap_or_else(|| "none".to_string()),
+            expected_expiration.clone().unwrap_or_else(|| "none".to_string()),
+        );
+        if expected_expiration.is_none() {
+            println!(
+                "Table {}: setupbq does not remove the partition expiration, remove it with bq update.",
+                definition.table_id,
+            );
+        }
+    }
+    let is_expiration_updated = is_expiration_changed && expected_expiration.is_some();
+
+    let expected_clustering = clustering(config, definition);
+    let actual_clustering = table.clustering.as_ref().and_then(|clustering| clustering.fields.clone());
+    let is_clustering_changed =
+        expected_clustering.as_ref().and_then(|clustering| clustering.fields.clone()) != actual_clustering;
+    if is_clustering_changed {
+        println!(
+            "Table {}: clustering is {:?} but expected {:?}.",
+            definition.table_id,
+            actual_clustering.unwrap_or_default(),
+            config.tables.clustering(definition),
+        );
+    }
+
+    let actual_fields = table.schema.fields.clone().unwrap_or_default();
+    let drift = schema::detect_drift(&definition.fields, &actual_fields);
+    if drift.is_empty() && !is_clustering_changed && !is_expiration_changed {
+        println!("Table {} is up to date.", definition.table_id);
+        return Ok(true);
+    }
+    for field in &drift.incompatible {
+        eprintln!("Table {}: {}", definition.table_id, field);
+    }
+    for field in &drift.added {
+        println!("Table {}: column {} is missing.", definition.table_id, field);
+    }
+    if (drift.added.is_empty() && !is_clustering_changed && !is_expiration_updated) || dry_run {
+        return Ok(drift.incompatible.is_empty());
+    }
+
+    //Only additive changes are applied, BigQuery rejects the rest
+    table.schema = TableSchema::new(
+        schema::merge_fields(&definition.fields, &actual_fields));
+    //New clustering only applies to the rows written from now on
+    table.clustering = Some(expected_clustering.unwrap_or_default());
+    if is_expiration_updated {
+        if let Some(partitioning) = table.time_partitioning.as_mut() {
+            partitioning.expiration_ms = expected_expiration;
+        }
+    }
+    client
+        .table()
+        .patch(&config.project_id, &config.dataset_id, definition.table_id, table)
+        .await?;
+    if !drift.added.is_empty() {
+        println!("Table {}: added {} column(s).", definition.table_id, drift.added.len());
+    }
+    if is_clustering_changed {
+        println!("Table {}: clustering updated.", definition.table_id);
+    }
+    if is_expiration_updated {
+        println!("Table {}: partition expiration updated.", definition.table_id);
+    }
+    return Ok(drift.incompatible.is_empty());
+}
 
 #[tokio::main]
 async fn main() -> Result<(), BQError> {
+    let matches = App::new("Setup BigQuery")
+        .version("0.1")
+        .author("Diego Wilson <diego.wilson.solis@gmail.com>")
+        .about("Create the dataset and tables of solistener and migrate their schema.")
+        .arg(Arg::with_name("config")
+            .long("config")
+            .short("c")
+            .env("SOLISTENER_CONFIG")
+            .value_name("FILE")
+            .help("TOML config file shared with solistener."))
+        .arg(Arg::with_name("project")
+            .long("project")
+            .short("p")
+            .value_name("PROJECT")
+            .help("Name of the GCP project."))
+        .arg(Arg::with_name("dataset")
+            .long("dataset")
+            .short("d")
+            .value_name("DATASET")
+            .help("Name of the dataset to create or migrate."))
+        .arg(Arg::with_name("location")
+            .long("location")
+            .value_name("LOCATION")
+            .help("Location of the dataset if it has to be created."))
+        .arg(Arg::with_name("label")
+            .long("label")
+            .multiple(true)
+            .number_of_values(1)
+            .value_name("KEY=VALUE")
+            .help("Label of the dataset and tables. Can be repeated."))
+        .arg(Arg::with_name("dry_run")
+            .long("dry-run")
+            .help("Report missing tables and schema drift without changing anything."))
+        .get_matches();
+
+    let mut config = Config::load(matches.value_of("config"))
+        .expect("Failed to load the config");
+    if let Some(project_id) = matches.value_of("project") {
+        config.project_id = project_id.to_string();
+    }
+    if let Some(dataset_id) = matches.value_of("dataset") {
+        config.dataset_id = dataset_id.to_string();
+    }
+    if let Some(location) = matches.value_of("location") {
+        config.tables.location = Some(location.to_string());
+    }
+    if let Some(labels) = matches.values_of("label") {
+        for label in labels {
+            let parts: Vec<&str> = label.splitn(2, '=').collect();
+            if parts.len() != 2 {
+                panic!("Label {} is not in the KEY=VALUE format", label);
+            }
+            config.tables.labels.insert(parts[0].to_string(), parts[1].to_string());
+        }
+    }
+    let dry_run = matches.is_present("dry_run");
+    if let Err(err) = config.validate() {
+        eprintln!("{}", err);
+        process::exit(1);
+    }
+
     let gcp_key = env::var("GOOGLE_APPLICATION_CREDENTIALS")
             .expect("Environment variable GOOGLE_APPLICATION_CREDENTIALS is required");
     // Init BigQuery client
     let client = gcp_bigquery_client::Client::from_service_account_key_file(&gcp_key).await;
 
-    let dataset = client.dataset().get(PROJECT_ID, DATASET_ID).await?;
-
-    let mut pre_balance_schema = TableFieldSchema::record(
-        "pre_token_balances",
-        vec![
-            TableFieldSchema::string("mint"),
-            TableFieldSchema::numeric("amount"),
-        ]
-    );
-    pre_balance_schema.mode = Some("REPEATED".to_string());
-
-    let mut post_balance_schema = TableFieldSchema::record(
-        "post_token_balances",
-        vec![
-            TableFieldSchema::string("mint"),
-            TableFieldSchema::numeric("amount"),
-        ]
-    );
-    post_balance_schema.mode = Some("REPEATED".to_string());
-
-    let mut account_schema = TableFieldSchema::record(
-        "accounts",
-        vec![
-            TableFieldSchema::string("address"),
-            TableFieldSchema::integer("pre_sol_balance"),
-            TableFieldSchema::integer("post_sol_balance"),
-            pre_balance_schema,
-            post_balance_schema,
-        ]
-    );
-    account_schema.mode = Some("REPEATED".to_string());
-
-    let mut instruction_accounts_schema = TableFieldSchema::string("accounts");
-    instruction_accounts_schema.mode = Some("REPEATED".to_string());
-
-    let mut instruction_schema = TableFieldSchema::record(
-        "instructions",
-        vec![
-            TableFieldSchema::string("program_id"),
-            instruction_accounts_schema,
-            TableFieldSchema::bytes("data"),
-        ]
-    );
-    instruction_schema.mode = Some("REPEATED".to_string());
-
-    let mut log_schema = TableFieldSchema::string("log_messages");
-    log_schema.mode = Some("REPEATED".to_string());
-
-    // Create a new table
-    let transaction_table = dataset
-        .create_table(
-            &client,
-            Table::from_dataset(
-                &dataset,
-                TRANSACTION_TABLE_ID,
-                TableSchema::new(vec![
-                    TableFieldSchema::timestamp("block_timestamp"),
-                    TableFieldSchema::integer("slot"),
-                    TableFieldSchema::string("transaction_id"),
-                    TableFieldSchema::bool("is_successful"),
-                    TableFieldSchema::string("error"),
-                    TableFieldSchema::integer("fee"),
-                    account_schema,
-                    instruction_schema,
-                    log_schema,
-                ]),
-            )
-            .friendly_name("Transactions")
-            .description("Solana ledger transactions")
-            .label("owner", "me")
-            .label("env", "prod")
-            .time_partitioning(
-                TimePartitioning::per_day()
-                    .field("block_timestamp"),
-            ),
-        )
-        .await?;
-    println!("Table created -> {:?}", transaction_table);
-
-    let mut reward_schema = TableFieldSchema::record(
-        "rewards",
-        vec![
-            TableFieldSchema::string("pubkey"),
-            TableFieldSchema::integer("lamports"),
-            TableFieldSchema::integer("post_balance"),
-            TableFieldSchema::string("reward_type"),
-        ]
-    );
-    reward_schema.mode = Some("REPEATED".to_string());
-
-    // Create a new table
-    let block_table = dataset
-        .create_table(
-            &client,
-            Table::from_dataset(
-                &dataset,
-                BLOCK_TABLE_ID,
-                TableSchema::new(vec![
-                    TableFieldSchema::timestamp("block_timestamp"),
-                    TableFieldSchema::integer("slot"),
-                    TableFieldSchema::integer("parent_slot"),
-                    TableFieldSchema::string("blockhash"),
-                    TableFieldSchema::string("previous_blockhash"),
-                    reward_schema,
-                ]),
-            )
-            .friendly_name("Blocks")
-            .description("Solana ledger blocks")
-            .label("owner", "me")
-            .label("env", "prod")
-            .time_partitioning(
-                TimePartitioning::per_day()
-                    .field("block_timestamp"),
-            ),
-        )
-        .await?;
+    let dataset = ensure_dataset(&client, &config, dry_run).await?;
 
-    println!("Table created -> {:?}", block_table);
+    let mut is_compatible = true;
+    for definition in schema::tables() {
+        if !migrate_table(&client, &config, &dataset, &definition, dry_run).await? {
+            is_compatible = false;
+        }
+    }
 
+    if !is_compatible {
+        eprintln!("One or more tables have incompatible columns that need a manual migration.");
+        process::exit(1);
+    }
     Ok(())
 }
@@ -1,52 +1,119 @@
+use std::collections::HashSet;
+
 use chrono::{
     DateTime,
     NaiveDateTime,
     Utc,
 };
 use serde::Serialize;
-use solana_sdk::clock::Slot;
-use solana_transaction_status::EncodedConfirmedBlock;
-
-#[derive(Serialize)]
-pub struct Block {
-    block_timestamp: Option<DateTime<Utc>>,
-    slot: u64,
-    parent_slot: u64,
-    blockhash: String,
-    previous_blockhash: String,
-    rewards: Vec<Reward>,
+use solana_sdk::clock::{
+    Slot,
+    UnixTimestamp,
+};
+use solana_sdk::{
+    pubkey::Pubkey,
+    transaction::Transaction as SolanaTransaction,
+};
+use solana_transaction_status::{
+    EncodedConfirmedBlock,
+    UiTransactionStatusMeta,
+};
+
+use crate::{
+    bigquery_record,
+    log_message::{
+        self,
+        Invocation,
+    },
+    vote,
+};
+
+bigquery_record! {
+    #[derive(Serialize)]
+    pub struct Block {
+        block_timestamp: Option<DateTime<Utc>>,
+        slot: u64,
+        parent_slot: u64,
+        blockhash: String,
+        previous_blockhash: String,
+        //Identity of the validator that produced the block
+        leader: Option<String>,
+        //Whether the previous blockhash is the hash of the previous block, None if unknown
+        parent_blockhash_matches: Option<bool>,
+        //Aggregates over every transaction of the block, whatever the filters
+        transaction_count: u64,
+        successful_transaction_count: u64,
+        failed_transaction_count: u64,
+        vote_transaction_count: u64,
+        non_vote_transaction_count: u64,
+        total_fee: u64,
+        total_compute_units: u64,
+        unique_signer_count: u64,
+        rewards: Vec<Reward>,
+    }
+}
+
+
+bigquery_record! {
+    #[derive(Serialize)]
+    struct Reward {
+        pubkey: String,
+        lamports: i64,
+        post_balance: u64,
+        reward_type: Option<String>,
+    }
 }
 
-#[derive(Serialize)]
-struct Reward {
-    pubkey: String,
-    lamports: i64,
-    post_balance: u64,
-    reward_type: Option<String>,
+
+//Range of a BigQuery TIMESTAMP, 0001-01-01 to 9999-12-31
+const MIN_TIMESTAMP: UnixTimestamp = -62_135_596_800;
+const MAX_TIMESTAMP: UnixTimestamp = 253_402_300_799;
+
+/// Time from a Unix timestamp, None when it cannot be recorded.
+/// Timestamps of votes are set by the validators and may be anything.
+pub fn timestamp(unix_timestamp: UnixTimestamp) -> Option<DateTime<Utc>> {
+    if unix_timestamp < MIN_TIMESTAMP || unix_timestamp > MAX_TIMESTAMP {
+        return None;
+    }
+    let naive_datetime = NaiveDateTime::from_timestamp_opt(unix_timestamp, 0)?;
+    return Some(DateTime::from_utc(naive_datetime, Utc));
+}
+
+/// Time of a block from its Unix timestamp, if the node knows it.
+pub fn block_timestamp(block_time: Option<UnixTimestamp>) -> Option<DateTime<Utc>> {
+    match block_time {
+        None => {
+            return None;
+        }
+        Some(bt) => {
+            return timestamp(bt);
+        }
+    }
 }
 
 impl Block {
     pub fn new(
         slot: Slot,
         encoded_block: &EncodedConfirmedBlock,
+        leader: Option<String>,
+        parent_blockhash_matches: Option<bool>,
     ) -> Block {
-        let block_timestamp: Option<DateTime<Utc>>;
-        match encoded_block.block_time {
-            None => {
-                block_timestamp = None;
-            }
-            Some(bt) => {
-                let naive_datetime = NaiveDateTime::from_timestamp(bt, 0);
-                block_timestamp = Some(DateTime::from_utc(naive_datetime, Utc));
-            }
-        }
-
         let mut block = Block {
-            block_timestamp: block_timestamp,
+            block_timestamp: block_timestamp(encoded_block.block_time),
             slot: slot,
             parent_slot: encoded_block.parent_slot,
             blockhash: encoded_block.blockhash.clone(),
             previous_blockhash: encoded_block.previous_blockhash.clone(),
+            leader: leader,
+            parent_blockhash_matches: parent_blockhash_matches,
+            transaction_count: 0,
+            successful_transaction_count: 0,
+            failed_transaction_count: 0,
+            vote_transaction_count: 0,
+            non_vote_transaction_count: 0,
+            total_fee: 0,
+            total_compute_units: 0,
+            unique_signer_count: 0,
             rewards: Vec::new(),
         };
 
@@ -73,4 +140,57 @@ impl Block {
     pub fn get_timestamp(&self) -> Option<DateTime<Utc>> {
         self.block_timestamp
     }
+
+    pub fn get_slot(&self) -> Slot {
+        self.slot
+    }
+
+    pub fn set_stats(&mut self, stats: BlockStats) {
+        self.transaction_count = stats.transaction_count;
+        self.successful_transaction_count = stats.successful_transaction_count;
+        self.failed_transaction_count = stats.transaction_count - stats.successful_transaction_count;
+        self.vote_transaction_count = stats.vote_transaction_count;
+        self.non_vote_transaction_count = stats.transaction_count - stats.vote_transaction_count;
+        self.total_fee = stats.total_fee;
+        self.total_compute_units = stats.total_compute_units;
+        self.unique_signer_count = stats.signers.len() as u64;
+    }
+}
+
+/// Aggregates of the transactions of a block, added up while the
+/// block is processed.
+#[derive(Default)]
+pub struct BlockStats {
+    transaction_count: u64,
+    successful_transaction_count: u64,
+    vote_transaction_count: u64,
+    total_fee: u64,
+    total_compute_units: u64,
+    signers: HashSet<Pubkey>,
+}
+
+impl BlockStats {
+    /// Add a transaction and the invocations parsed from its log messages.
+    pub fn add(
+        &mut self,
+        meta: &UiTransactionStatusMeta,
+        solana_transaction: &SolanaTransaction,
+        invocations: &[Vec<Invocation>],
+    ) {
+        self.transaction_count += 1;
+        if meta.status.is_ok() {
+            self.successful_transaction_count += 1;
+        }
+        if vote::is_vote_transaction(solana_transaction) {
+            self.vote_transaction_count += 1;
+        }
+        self.total_fee += meta.fee;
+        let consumed = log_message::compute_units_consumed(invocations.iter().flatten());
+        self.total_compute_units += consumed.unwrap_or(0);
+        let message = &solana_transaction.message;
+        let signer_count = message.header.num_required_signatures as usize;
+        for signer in message.account_keys.iter().take(signer_count) {
+            self.signers.insert(*signer);
+        }
+    }
 }
@@ -1,4 +1,6 @@
 use std::{
+    collections::HashSet,
+    sync::Arc,
     thread,
     time,
 };
@@ -8,7 +10,12 @@ use chrono::{
     Utc,
 };
 
-use num_cpus;
+use tracing::{
+    error,
+    info,
+    info_span,
+    warn,
+};
 
 use solana_rpc::SolanaRpc;
 
@@ -23,74 +30,100 @@ use solana_sdk::{
 
 use solana_transaction_status::{
     EncodedConfirmedBlock,
-    UiTransactionEncoding,
     UiTransactionStatusMeta,
 };
 
 use crate::{
+    backfill::ShardProgress,
+    balance_change::BalanceChange,
+    batch_writer::{
+        BatchSender,
+        BatchWriter,
+        BlockRows,
+    },
     bigquery::BigQuery,
-    block::Block,
-    counter::Counter,
+    block::{
+        Block,
+        BlockStats,
+    },
+    concurrency::Concurrency,
+    continuity::{
+        self,
+        ContinuityMode,
+        ContinuityValidator,
+    },
+    config::Config,
+    filter::TransactionFilter,
+    health::HEALTH,
+    leader_schedule::LeaderSchedule,
+    log_message::{
+        self,
+        Invocation,
+    },
+    metrics::{
+        LATEST_SLOT,
+        PROCESSED_SLOT,
+        SLOT_LAG,
+    },
+    prefetch::Prefetcher,
+    slot_status::SlotStatus,
     solana_rpc,
     transaction::Transaction,
+    transfer::Transfer,
+    vote::{
+        Vote,
+        VoteMode,
+    },
 };
 
-const SLOTS_BEHIND_LATEST: u64 = 200;
-const MAX_SLOT_RANGE: u64 = 100;
-
 pub struct Listener {
+    config: Arc<Config>,
     solana_client: SolanaRpc,
-    project_id: String,
-    dataset_id: String,
     processed_slot: Slot,
-    end_slot: Option<Slot>,
-    max_processor_count: usize,
-    processor_counter: Counter,
+    concurrency: Concurrency,
+    prefetcher: Prefetcher,
+    leader_schedule: LeaderSchedule,
+    continuity: ContinuityValidator,
+    filter: TransactionFilter,
+    //Taken when the listener stops to write the last rows
+    writer: Option<BatchWriter>,
+    //Set when the listener processes a shard of a backfill
+    progress: Option<ShardProgress>,
+    //Set when rows of the shard were dropped by the writer
+    is_write_failed: bool,
 }
 
 impl Listener {
-    fn get_block(&self, slot: Slot) -> EncodedConfirmedBlock {
-        let mut period = time::Duration::from_millis(100);
-        loop {
-            let block_result = self.solana_client.get_block_with_encoding(
-                slot, UiTransactionEncoding::Base64);
-            if let Ok(block) = block_result {
-                return block;
-            }
-            println!("Attempt to get block failed. Retry after {} ms.",
-                period.as_millis());
-            thread::sleep(period);
-            //Use exponential backoff
-            period *= 2;
-        }
-    }
-
-    fn get_unprocessed_slots(&mut self) -> Vec<Slot> {
+    // Blocks after the processed slot, up to the returned target slot
+    fn get_unprocessed_slots(&mut self) -> (Vec<Slot>, Slot) {
         let latest_slot = self.solana_client.get_latest_slot();
+        LATEST_SLOT.set(latest_slot as i64);
+        SLOT_LAG.set(latest_slot.saturating_sub(self.processed_slot) as i64);
 
-
-        if self.processed_slot + SLOTS_BEHIND_LATEST >= latest_slot {
+        let slots_behind_latest = self.config.slots_behind_latest;
+        if self.processed_slot + slots_behind_latest >= latest_slot {
             let empty_slots: Vec<Slot> = vec![];
-            return empty_slots;
+            return (empty_slots, self.processed_slot);
         }
 
-        let mut target_slot = latest_slot - SLOTS_BEHIND_LATEST;
-        if target_slot - self.processed_slot > MAX_SLOT_RANGE {
-            target_slot = self.processed_slot + MAX_SLOT_RANGE;
+        let mut target_slot = latest_slot - slots_behind_latest;
+        if target_slot - self.processed_slot > self.config.max_slot_range {
+            target_slot = self.processed_slot + self.config.max_slot_range;
         }
-        if let Some(end_slot) = self.end_slot {
+        if let Some(end_slot) = self.config.end_slot {
             if target_slot > end_slot {
                 target_slot = end_slot;
             }
         }
 
-        println!("Latest slot: {}. Target slot: {}. Processed slot: {}. Trailing latest {}. Trailing target: {}.",
+        info!(
             latest_slot,
             target_slot,
-            self.processed_slot,
-            latest_slot - self.processed_slot,
-            target_slot - self.processed_slot,
-            );
+            processed_slot = self.processed_slot,
+            trailing_latest = latest_slot - self.processed_slot,
+            trailing_target = target_slot - self.processed_slot,
+            "Fetch unprocessed slots."
+        );
 
         let mut period = time::Duration::from_millis(100);
         loop {
@@ -98,13 +131,17 @@ impl Listener {
                 self.processed_slot + 1, Some(target_slot));
             match slots_result {
                 Ok(slots) => {
-                    return slots;
+                    return (slots, target_slot);
                 }
                 Err(error) => {
-                    println!("Attempt to fetch list of pending slots failed with error: {:?}", error);
-                    println!("Retry after {} ms.", period.as_millis());
+                    warn!(
+                        error = ?error,
+                        retry_ms = period.as_millis() as u64,
+                        "Attempt to fetch list of pending slots failed. Retry."
+                    );
                 }
             }
+            solana_rpc::record_failed_retry(period);
             thread::sleep(period);
             //Use exponential backoff
             period *= 2;
@@ -112,115 +149,344 @@ impl Listener {
     }
 
 
+    // Record the slots of the range without a block as skipped. Produced
+    // slots are recorded by the processors along with the rows of their block.
+    fn record_skipped_slots(
+        &mut self,
+        first_slot: Slot,
+        last_slot: Slot,
+        produced_slots: &HashSet<Slot>) {
+
+        let mut slots: Vec<SlotStatus> = Vec::new();
+        for slot in first_slot..=last_slot {
+            if !produced_slots.contains(&slot) {
+                let leader = self.leader_schedule.get_leader(&mut self.solana_client, slot);
+                slots.push(SlotStatus::skipped(slot, leader));
+            }
+        }
+        if slots.is_empty() {
+            return;
+        }
+        self.writer
+            .as_ref()
+            .expect("Batch writer is closed")
+            .sender()
+            .send_slots(slots);
+    }
+
     fn process_slots(&mut self) -> bool {
-        if let Some(end_slot) = self.end_slot {
+        if continuity::is_halted() {
+            return false;
+        }
+        if let Some(end_slot) = self.config.end_slot {
             if self.processed_slot >= end_slot {
-                println!("Stop after processing the selected end slot {}", end_slot);
+                info!(end_slot, "Stop after processing the selected end slot.");
                 return false;
             }
         }
 
-        let all_unprocessed_slots = self.get_unprocessed_slots();
+        let (all_unprocessed_slots, target_slot) = self.get_unprocessed_slots();
 
         const NO_UNPROCESSED_SLOTS_WAIT: std::time::Duration = time::Duration::from_millis(1000);
-        if all_unprocessed_slots.is_empty() {
+        if target_slot <= self.processed_slot {
             thread::sleep(NO_UNPROCESSED_SLOTS_WAIT);
-            return true;
+            return !self.is_lease_lost();
         }
 
-        for slot in all_unprocessed_slots.into_iter() {
-            let block = self.get_block(slot);
-            self.processor_counter.wait_if_above(self.max_processor_count - 1);
-            self.processor_counter.increase();
-            let project_id = self.project_id.clone();
-            let dataset_id = self.dataset_id.clone();
-            let processor_counter = self.processor_counter.clone();
+        let first_slot = self.processed_slot + 1;
+        let mut produced_slots: HashSet<Slot> = HashSet::new();
+        for (slot, block) in self.prefetcher.fetch(all_unprocessed_slots) {
+            //Rows of a range taken over by another worker are not handed to the writer
+            if self.is_lease_lost() {
+                return false;
+            }
+            let parent_blockhash_matches: Option<bool>;
+            match self.continuity.check(slot, &block) {
+                Ok(matches) => {
+                    parent_blockhash_matches = matches;
+                }
+                Err(err) => {
+                    error!(slot, error = %err, "Stop before recording the block.");
+                    break;
+                }
+            }
+            produced_slots.insert(slot);
+            let leader = self.leader_schedule.get_leader(&mut self.solana_client, slot);
+            let permit = self.concurrency.acquire();
+            let config = Arc::clone(&self.config);
+            let filter = self.filter.clone();
+            let sender = self.writer
+                .as_ref()
+                .expect("Batch writer is closed")
+                .sender();
             thread::spawn(move || {
-                let processor = Processor::new(&project_id, &dataset_id);
-                processor.process_block(slot, block)
+                let span = info_span!("process_block", slot);
+                let _enter = span.enter();
+                let processor = Processor::new(&config, filter, sender);
+                processor.process_block(slot, block, leader, parent_blockhash_matches)
                     .expect("Failed to process block");
-                processor_counter.decrease();
+                drop(permit);
             });
             self.processed_slot = slot;
+            PROCESSED_SLOT.set(slot as i64);
+            HEALTH.record_progress();
+        }
+        //The range stops at the last recorded block when halted
+        let is_halted = continuity::is_halted();
+        let last_slot = if is_halted { self.processed_slot } else { target_slot };
+        //Slots up to the last slot without a block were skipped
+        if self.config.record_slots {
+            self.record_skipped_slots(first_slot, last_slot, &produced_slots);
         }
+        self.processed_slot = last_slot;
+        PROCESSED_SLOT.set(last_slot as i64);
+        //Ranges of skipped slots only are progress too
+        HEALTH.record_progress();
 
+        if self.progress.is_some() && !self.save_progress() {
+            return false;
+        }
+        return !is_halted;
+    }
+
+    // Whether another worker took over the lease of the shard. The lease
+    // is renewed in the background, independently of the writes.
+    fn is_lease_lost(&self) -> bool {
+        match &self.progress {
+            Some(progress) => {
+                if progress.is_owned() {
+                    return false;
+                }
+                warn!(
+                    first_slot = progress.first_slot,
+                    last_slot = progress.last_slot,
+                    "Lease of the range was taken over by another worker. Stop."
+                );
+                return true;
+            }
+            None => {
+                return false;
+            }
+        }
+    }
+
+    // Record the progress of the shard once the rows of its blocks are written.
+    // Returns false if the rows were not written or if another worker took
+    // over the lease of the shard.
+    fn save_progress(&mut self) -> bool {
+        self.concurrency.wait_idle();
+        if let Some(writer) = &self.writer {
+            if !writer.sender().flush() {
+                //Resumed from the saved progress instead of leaving a gap
+                error!(
+                    processed_slot = self.processed_slot,
+                    "Rows of the shard were not written. Stop without saving the progress."
+                );
+                self.is_write_failed = true;
+                return false;
+            }
+        }
+        if let Some(progress) = &mut self.progress {
+            let is_owned = progress.save(self.processed_slot)
+                .expect("Failed to save the shard progress");
+            if !is_owned {
+                warn!(
+                    first_slot = progress.first_slot,
+                    last_slot = progress.last_slot,
+                    "Lease of the range was taken over by another worker. Stop."
+                );
+                return false;
+            }
+        }
         return true;
     }
 
-    pub fn listen(&mut self) {
+    /// Process slots until the end slot. The rows of the processed blocks
+    /// are written before it returns, even when it fails.
+    pub fn listen(&mut self) -> Result<(), String> {
         while self.process_slots() {}
-        self.processor_counter.wait_if_above(0);
+        self.concurrency.wait_idle();
+        if let Some(writer) = self.writer.take() {
+            writer.close();
+        }
+        if continuity::is_halted() {
+            return Err(format!(
+                "Stopped after slot {} on a broken blockhash chain",
+                self.processed_slot,
+            ));
+        }
+        if self.is_write_failed {
+            return Err(format!(
+                "Stopped at slot {} since rows of the shard were not written",
+                self.processed_slot,
+            ));
+        }
+        return Ok(());
     }
 
-    pub fn new(
-        project_id: &str,
-        dataset_id: &str,
-        start_slot: Option<Slot>,
-        end_slot: Option<Slot>,
-    ) -> Listener {
-        let mut solana_client = SolanaRpc::new();
+    pub fn new(config: Config) -> Result<Listener, String> {
+        let filter = config.transaction_filter()
+            .expect("Transaction filter is not valid");
+        let mut solana_client = SolanaRpc::new(&config.rpc_endpoints);
         let processed_slot: Slot;
-        if let Some(start) = start_slot {
+        //Latest stored block, the first block must extend it
+        let mut stored_block: Option<(Slot, String)> = None;
+        if let Some(start) = config.start_slot {
             processed_slot = start - 1;
-            println!("Start from selected slot {}", start);
+            info!(slot = start, "Start from selected slot.");
         }
         else {
-            let bq_client = BigQuery::new(project_id, dataset_id);
+            let bq_client = BigQuery::new(&config)?;
             if let Ok(slot) = bq_client.get_latest_slot() {
                 processed_slot = slot;
-                println!("Resume from latest processed slot {}", processed_slot);
+                info!(slot = processed_slot, "Resume from latest processed slot.");
+                if config.continuity != ContinuityMode::Off {
+                    stored_block = bq_client.get_latest_block().ok();
+                }
             } else {
                 processed_slot = solana_client.get_latest_slot()
-                    - SLOTS_BEHIND_LATEST;
-                println!("Could not find any previously processed slots.");
-                println!("Start at the latest live slot {}", processed_slot);
+                    - config.slots_behind_latest;
+                info!(slot = processed_slot,
+                    "Could not find any previously processed slots. Start at the latest live slot.");
             }
         }
-        let max_processor_count = num_cpus::get() * 2;
-        Listener {
+        let concurrency = Concurrency::processors(&config);
+        let writer = BatchWriter::new(&config)?;
+        let prefetcher = Prefetcher::new(&config);
+        let continuity = ContinuityValidator::new(config.continuity, stored_block);
+        return Ok(Listener {
+            config: Arc::new(config),
             solana_client: solana_client,
-            project_id: project_id.to_string(),
-            dataset_id: dataset_id.to_string(),
             processed_slot: processed_slot,
-            end_slot: end_slot,
-            max_processor_count: max_processor_count,
-            processor_counter: Counter::new(),
-        }
+            prefetcher: prefetcher,
+            leader_schedule: LeaderSchedule::new(),
+            continuity: continuity,
+            concurrency: concurrency,
+            filter: filter,
+            writer: Some(writer),
+            progress: None,
+            is_write_failed: false,
+        });
+    }
+
+    /// Listener of one shard of a backfill. It resumes after the slot
+    /// saved in the progress of the shard and stops at its last slot.
+    pub fn for_shard(mut config: Config, progress: ShardProgress) -> Result<Listener, String> {
+        let filter = config.transaction_filter()
+            .expect("Transaction filter is not valid");
+        let processed_slot = progress.processed_slot();
+        config.start_slot = Some(processed_slot + 1);
+        config.end_slot = Some(progress.last_slot);
+        info!(
+            first_slot = progress.first_slot,
+            last_slot = progress.last_slot,
+            processed_slot,
+            "Start shard."
+        );
+        let solana_client = SolanaRpc::new(&config.rpc_endpoints);
+        let concurrency = Concurrency::processors(&config);
+        let writer = BatchWriter::new(&config)?;
+        let prefetcher = Prefetcher::new(&config);
+        //Blocks of a shard follow blocks processed by other shards
+        let continuity = ContinuityValidator::new(config.continuity, None);
+        return Ok(Listener {
+            config: Arc::new(config),
+            solana_client: solana_client,
+            processed_slot: processed_slot,
+            prefetcher: prefetcher,
+            leader_schedule: LeaderSchedule::new(),
+            continuity: continuity,
+            concurrency: concurrency,
+            filter: filter,
+            writer: Some(writer),
+            progress: Some(progress),
+            is_write_failed: false,
+        });
     }
 }
 
 struct Processor {
-    bq_client: BigQuery,
+    sender: BatchSender,
+    vote_mode: VoteMode,
+    filter: TransactionFilter,
+    record_slots: bool,
 }
 
 impl Processor {
-    pub fn new(project_id: &str, dataset_id: &str) -> Processor {
+    pub fn new(config: &Config, filter: TransactionFilter, sender: BatchSender) -> Processor {
         Processor {
-            bq_client: BigQuery::new(project_id, dataset_id),
+            sender: sender,
+            vote_mode: config.votes,
+            filter: filter,
+            record_slots: config.record_slots,
         }
     }
 
     fn process_transaction(
-        &mut self,
+        &self,
+        rows: &mut BlockRows,
         block_timestamp: &Option<DateTime<Utc>>,
         slot: Slot,
         meta: &UiTransactionStatusMeta,
-        solana_transaction: &SolanaTransaction) {
+        solana_transaction: &SolanaTransaction,
+        invocations: Vec<Vec<Invocation>>) {
+
+        //Vote program transactions that do not cast a vote are recorded
+        //as transactions whatever the mode
+        if self.vote_mode != VoteMode::Include {
+            if let Some(vote) = Vote::from_transaction(
+                block_timestamp,
+                slot,
+                meta,
+                solana_transaction,
+            ) {
+                if self.vote_mode == VoteMode::Separate {
+                    rows.votes.push(vote);
+                }
+                return;
+            }
+        }
 
         let transaction = Transaction::new(
             block_timestamp,
             slot,
             meta,
             solana_transaction,
+            invocations,
+        );
+        let transfers = Transfer::from_transaction(
+            block_timestamp,
+            slot,
+            meta,
+            solana_transaction,
+        );
+        let balance_changes = BalanceChange::from_transaction(
+            block_timestamp,
+            slot,
+            meta,
+            solana_transaction,
         );
 
-        self.bq_client.add_transaction(transaction);
+        rows.transactions.push(transaction);
+        rows.transfers.extend(transfers);
+        rows.balance_changes.extend(balance_changes);
     }
 
-    fn process_block(mut self, slot: Slot, encoded_block: EncodedConfirmedBlock) -> ClientResult<String> {
-        let block = Block::new(slot, &encoded_block);
+    fn process_block(
+        self,
+        slot: Slot,
+        encoded_block: EncodedConfirmedBlock,
+        leader: Option<String>,
+        parent_blockhash_matches: Option<bool>) -> ClientResult<String> {
+
+        let block = Block::new(slot, &encoded_block, leader.clone(), parent_blockhash_matches);
         let timestamp = block.get_timestamp();
-        self.bq_client.add_block(block);
+        let mut rows = BlockRows::new(block);
+        //Written with the block, so that a produced slot always has its block
+        if self.record_slots {
+            rows.slot = Some(SlotStatus::produced(slot, timestamp, leader));
+        }
+        let mut stats = BlockStats::default();
 
         for rpc_transaction in encoded_block.transactions {
             match rpc_transaction.meta {
@@ -230,7 +496,16 @@ impl Processor {
                 Some(meta) => {
                     if let Some(transaction) = rpc_transaction.transaction.decode() {
                         if transaction.verify().is_ok() {
-                            self.process_transaction(&timestamp, slot, &meta, &transaction);
+                            //Parsed once for the block stats and the transaction row
+                            let invocations = match &meta.log_messages {
+                                Some(messages) => log_message::parse_invocations(messages),
+                                None => Vec::new(),
+                            };
+                            stats.add(&meta, &transaction, &invocations);
+                            if self.filter.is_match(&meta, &transaction) {
+                                self.process_transaction(
+                                    &mut rows, &timestamp, slot, &meta, &transaction, invocations);
+                            }
                         } else {
                             panic!("Transaction signature verification failed");
                         }
@@ -240,7 +515,8 @@ impl Processor {
                 }
             }
         }
-        self.bq_client.commit();
+        rows.block.set_stats(stats);
+        self.sender.send(rows);
         Ok("".to_string())
     }
 }
@@ -0,0 +1,191 @@
+use std::convert::TryInto;
+
+use solana_sdk::transaction::Transaction as SolanaTransaction;
+
+const COMPUTE_BUDGET_PROGRAM_ID: &str = "ComputeBudget111111111111111111111111111111";
+const DEFAULT_INSTRUCTION_COMPUTE_UNIT_LIMIT: u64 = 200_000;
+const MAX_COMPUTE_UNIT_LIMIT: u64 = 1_400_000;
+const MICRO_LAMPORTS_PER_LAMPORT: u128 = 1_000_000;
+
+//Instruction discriminants of the ComputeBudget program
+const REQUEST_UNITS_DEPRECATED: u8 = 0;
+const SET_COMPUTE_UNIT_LIMIT: u8 = 2;
+const SET_COMPUTE_UNIT_PRICE: u8 = 3;
+
+/// Compute budget requested by a transaction through
+/// ComputeBudget program instructions.
+pub struct ComputeBudget {
+    pub compute_unit_limit: u64,
+    //Price per compute unit in micro-lamports
+    pub compute_unit_price: u64,
+    //Priority fee in lamports
+    pub priority_fee: u64,
+}
+
+fn read_u32(data: &[u8], offset: usize) -> Option<u64> {
+    let bytes = data.get(offset..offset + 4)?;
+    return Some(u32::from_le_bytes(bytes.try_into().ok()?) as u64);
+}
+
+fn read_u64(data: &[u8], offset: usize) -> Option<u64> {
+    let bytes = data.get(offset..offset + 8)?;
+    return Some(u64::from_le_bytes(bytes.try_into().ok()?));
+}
+
+impl ComputeBudget {
+    pub fn new(solana_transaction: &SolanaTransaction) -> ComputeBudget {
+        let message = &solana_transaction.message;
+        let mut requested_limit: Option<u64> = None;
+        let mut compute_unit_price: u64 = 0;
+        //Deprecated RequestUnits instructions set the fee directly
+        let mut additional_fee: Option<u64> = None;
+        let mut other_instruction_count: u64 = 0;
+
+        for instruction in &message.instructions {
+            let program_id = message.account_keys[instruction.program_id_index as usize]
+                .to_string();
+            if program_id != COMPUTE_BUDGET_PROGRAM_ID {
+                other_instruction_count += 1;
+                continue;
+            }
+            let data = &instruction.data[..];
+            match data.first() {
+                Some(&REQUEST_UNITS_DEPRECATED) => {
+                    requested_limit = read_u32(data, 1);
+                    additional_fee = read_u32(data, 5);
+                }
+                Some(&SET_COMPUTE_UNIT_LIMIT) => {
+                    requested_limit = read_u32(data, 1);
+                }
+                Some(&SET_COMPUTE_UNIT_PRICE) => {
+                    if let Some(price) = read_u64(data, 1) {
+                        compute_unit_price = price;
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        let compute_unit_limit = requested_limit
+            .unwrap_or(other_instruction_count * DEFAULT_INSTRUCTION_COMPUTE_UNIT_LIMIT)
+            .min(MAX_COMPUTE_UNIT_LIMIT);
+
+        let priority_fee: u64;
+        match additional_fee {
+            Some(fee) => {
+                priority_fee = fee;
+            }
+            None => {
+                //Round up to the next lamport
+                let micro_lamports = compute_unit_limit as u128 * compute_unit_price as u128;
+                priority_fee = ((micro_lamports + MICRO_LAMPORTS_PER_LAMPORT - 1)
+                    / MICRO_LAMPORTS_PER_LAMPORT) as u64;
+            }
+        }
+
+        return ComputeBudget {
+            compute_unit_limit: compute_unit_limit,
+            compute_unit_price: compute_unit_price,
+            priority_fee: priority_fee,
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use solana_sdk::{
+        instruction::Instruction,
+        message::Message,
+        pubkey::Pubkey,
+    };
+
+    use super::*;
+
+    fn instruction(program_id: &str, data: Vec<u8>) -> Instruction {
+        let program_id = Pubkey::from_str(program_id).unwrap();
+        return Instruction::new_with_bytes(program_id, &data, Vec::new());
+    }
+
+    fn compute_budget(discriminant: u8, args: &[&[u8]]) -> Instruction {
+        let mut data = vec![discriminant];
+        for arg in args {
+            data.extend_from_slice(arg);
+        }
+        return instruction(COMPUTE_BUDGET_PROGRAM_ID, data);
+    }
+
+    fn memo() -> Instruction {
+        return instruction("MemoSq4gqABAXKb96qnH8TysNcWxMyWCqXgDLGmfcHr", vec![1, 2, 3]);
+    }
+
+    fn budget(instructions: &[Instruction]) -> ComputeBudget {
+        let payer = Pubkey::new_unique();
+        let message = Message::new(instructions, Some(&payer));
+        return ComputeBudget::new(&SolanaTransaction::new_unsigned(message));
+    }
+
+    #[test]
+    fn default_limit_without_budget_instructions() {
+        let budget = budget(&[memo(), memo()]);
+        assert_eq!(budget.compute_unit_limit, 2 * DEFAULT_INSTRUCTION_COMPUTE_UNIT_LIMIT);
+        assert_eq!(budget.compute_unit_price, 0);
+        assert_eq!(budget.priority_fee, 0);
+    }
+
+    #[test]
+    fn set_compute_unit_limit_and_price() {
+        let budget = budget(&[
+            compute_budget(SET_COMPUTE_UNIT_LIMIT, &[&300_000u32.to_le_bytes()]),
+            compute_budget(SET_COMPUTE_UNIT_PRICE, &[&1_500u64.to_le_bytes()]),
+            memo(),
+        ]);
+        assert_eq!(budget.compute_unit_limit, 300_000);
+        assert_eq!(budget.compute_unit_price, 1_500);
+        assert_eq!(budget.priority_fee, 450);
+    }
+
+    #[test]
+    fn priority_fee_rounds_up() {
+        let budget = budget(&[
+            compute_budget(SET_COMPUTE_UNIT_LIMIT, &[&1_001u32.to_le_bytes()]),
+            compute_budget(SET_COMPUTE_UNIT_PRICE, &[&1_000u64.to_le_bytes()]),
+        ]);
+        assert_eq!(budget.priority_fee, 2);
+    }
+
+    #[test]
+    fn compute_unit_limit_is_capped() {
+        let budget = budget(&[
+            compute_budget(SET_COMPUTE_UNIT_LIMIT, &[&2_000_000u32.to_le_bytes()]),
+            memo(),
+        ]);
+        assert_eq!(budget.compute_unit_limit, MAX_COMPUTE_UNIT_LIMIT);
+    }
+
+    #[test]
+    fn deprecated_request_units() {
+        let budget = budget(&[
+            compute_budget(REQUEST_UNITS_DEPRECATED, &[
+                &500_000u32.to_le_bytes(),
+                &7_000u32.to_le_bytes(),
+            ]),
+            memo(),
+        ]);
+        assert_eq!(budget.compute_unit_limit, 500_000);
+        assert_eq!(budget.compute_unit_price, 0);
+        assert_eq!(budget.priority_fee, 7_000);
+    }
+
+    #[test]
+    fn truncated_instruction_data_is_ignored() {
+        let budget = budget(&[
+            compute_budget(SET_COMPUTE_UNIT_PRICE, &[&[1, 2, 3]]),
+            memo(),
+        ]);
+        assert_eq!(budget.compute_unit_limit, DEFAULT_INSTRUCTION_COMPUTE_UNIT_LIMIT);
+        assert_eq!(budget.compute_unit_price, 0);
+        assert_eq!(budget.priority_fee, 0);
+    }
+}
@@ -0,0 +1,279 @@
+use std::{
+    sync::{
+        Arc,
+        Condvar,
+        Mutex,
+    },
+    time::{
+        Duration,
+        Instant,
+    },
+};
+
+use prometheus::IntGauge;
+use tracing::{
+    debug,
+    info,
+};
+
+use crate::{
+    config::Config,
+    metrics::{
+        ACTIVE_BLOCK_REQUESTS,
+        ACTIVE_PROCESSORS,
+        BLOCK_REQUEST_LIMIT,
+        PROCESSOR_LIMIT,
+    },
+};
+
+//Decreases within this time of the last one are caused by the same congestion
+const DECREASE_COOLDOWN: Duration = Duration::from_secs(1);
+//Pause between writes after the first slow or failed write
+const INITIAL_WRITE_DELAY: Duration = Duration::from_millis(500);
+//Removed from the pause after each write within the target
+const WRITE_DELAY_STEP: Duration = Duration::from_millis(100);
+const MAX_WRITE_DELAY: Duration = Duration::from_secs(60);
+
+struct State {
+    active: usize,
+    //Fractional so that the limit grows by one once that many requests succeed
+    limit: f64,
+    last_decrease: Option<Instant>,
+    limit_gauge: IntGauge,
+}
+
+impl State {
+    fn permits(&self) -> usize {
+        self.limit as usize
+    }
+}
+
+/// Semaphore of the block processors, or of the getBlock requests in
+/// flight. The limit of the requests adapts to the RPC endpoints: it grows
+/// by one per window of successful requests and is cut by the decrease
+/// factor on errors or slow requests (AIMD).
+#[derive(Clone)]
+pub struct Concurrency {
+    state: Arc<(Mutex<State>, Condvar)>,
+    name: &'static str,
+    active_gauge: IntGauge,
+    min_limit: f64,
+    max_limit: f64,
+    decrease_factor: f64,
+    rpc_latency_target: Duration,
+    is_adaptive: bool,
+}
+
+/// Held while a processor or a request runs, releases its slot when dropped.
+pub struct Permit {
+    concurrency: Concurrency,
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        let (lock, condvar) = &*self.concurrency.state;
+        let mut state = lock.lock().unwrap();
+        state.active -= 1;
+        self.concurrency.active_gauge.dec();
+        debug!(name = self.concurrency.name, active = state.active, "Permit released.");
+        condvar.notify_all();
+    }
+}
+
+impl Concurrency {
+    fn new(
+        config: &Config,
+        name: &'static str,
+        active_gauge: &IntGauge,
+        limit_gauge: &IntGauge,
+        min_limit: usize,
+        max_limit: usize,
+        is_adaptive: bool) -> Concurrency {
+
+        let max_limit = max_limit as f64;
+        let min_limit = (min_limit as f64).min(max_limit);
+        //Without adaptation the whole limit is used from the start
+        let limit = if is_adaptive { min_limit } else { max_limit };
+        limit_gauge.add(limit as i64);
+        Concurrency {
+            state: Arc::new((
+                Mutex::new(State {
+                    active: 0,
+                    limit: limit,
+                    last_decrease: None,
+                    limit_gauge: limit_gauge.clone(),
+                }),
+                Condvar::new(),
+            )),
+            name: name,
+            active_gauge: active_gauge.clone(),
+            min_limit: min_limit,
+            max_limit: max_limit,
+            decrease_factor: config.concurrency.decrease_factor,
+            rpc_latency_target: config.concurrency.rpc_latency_target(),
+            is_adaptive: is_adaptive,
+        }
+    }
+
+    /// Block processors, which only use the CPU. Their number is fixed.
+    pub fn processors(config: &Config) -> Concurrency {
+        return Concurrency::new(
+            config,
+            "processors",
+            &ACTIVE_PROCESSORS,
+            &PROCESSOR_LIMIT,
+            config.max_processor_count,
+            config.max_processor_count,
+            false,
+        );
+    }
+
+    /// getBlock requests in flight, up to the prefetch window.
+    pub fn block_requests(config: &Config) -> Concurrency {
+        return Concurrency::new(
+            config,
+            "block_requests",
+            &ACTIVE_BLOCK_REQUESTS,
+            &BLOCK_REQUEST_LIMIT,
+            config.concurrency.min_request_count,
+            config.prefetch_window,
+            config.concurrency.adaptive,
+        );
+    }
+
+    /// Wait for a free slot under the current limit.
+    pub fn acquire(&self) -> Permit {
+        let (lock, condvar) = &*self.state;
+        let mut state = lock.lock().unwrap();
+        while state.active >= state.permits() {
+            state = condvar.wait(state).unwrap();
+        }
+        state.active += 1;
+        self.active_gauge.inc();
+        debug!(name = self.name, active = state.active, limit = state.permits(), "Permit acquired.");
+        return Permit {
+            concurrency: self.clone(),
+        };
+    }
+
+    /// Wait until every permit is released.
+    pub fn wait_idle(&self) {
+        let (lock, condvar) = &*self.state;
+        let mut state = lock.lock().unwrap();
+        while state.active > 0 {
+            state = condvar.wait(state).unwrap();
+        }
+    }
+
+    fn set_limit(&self, state: &mut State, limit: f64) {
+        let limit = limit.max(self.min_limit).min(self.max_limit);
+        let previous = state.permits();
+        state.limit = limit;
+        let permits = state.permits();
+        if permits != previous {
+            state.limit_gauge.add(permits as i64 - previous as i64);
+            debug!(name = self.name, limit = permits, "Limit changed.");
+        }
+    }
+
+    fn increase(&self) {
+        if !self.is_adaptive {
+            return;
+        }
+        let (lock, condvar) = &*self.state;
+        let mut state = lock.lock().unwrap();
+        let limit = state.limit + 1.0 / state.limit;
+        self.set_limit(&mut state, limit);
+        condvar.notify_all();
+    }
+
+    fn decrease(&self, reason: &str) {
+        if !self.is_adaptive {
+            return;
+        }
+        let (lock, _) = &*self.state;
+        let mut state = lock.lock().unwrap();
+        if let Some(last_decrease) = state.last_decrease {
+            if last_decrease.elapsed() < DECREASE_COOLDOWN {
+                return;
+            }
+        }
+        let limit = state.limit * self.decrease_factor;
+        self.set_limit(&mut state, limit);
+        state.last_decrease = Some(Instant::now());
+        info!(name = self.name, reason, limit = state.permits(), "Back off requests.");
+    }
+
+    /// Adapt the limit to the outcome of an RPC request.
+    pub fn record_rpc(&self, latency: Duration, is_ok: bool) {
+        if !is_ok {
+            self.decrease("rpc_error");
+        } else if latency > self.rpc_latency_target {
+            self.decrease("rpc_latency");
+        } else {
+            self.increase();
+        }
+    }
+}
+
+impl Drop for State {
+    fn drop(&mut self) {
+        //Listeners of finished shards no longer count toward the limit
+        self.limit_gauge.sub(self.permits() as i64);
+    }
+}
+
+/// Pause of the batch writer after each write, so that the rate of the
+/// writes adapts to the sink. The rate is cut by the decrease factor when
+/// a write fails or is slower than the target, and grows back by a step
+/// after each write within it.
+pub struct WritePace {
+    delay: Duration,
+    decrease_factor: f64,
+    sink_latency_target: Duration,
+    is_adaptive: bool,
+}
+
+impl WritePace {
+    pub fn new(config: &Config) -> WritePace {
+        WritePace {
+            delay: Duration::from_secs(0),
+            decrease_factor: config.concurrency.decrease_factor,
+            sink_latency_target: config.concurrency.sink_latency_target(),
+            is_adaptive: config.concurrency.adaptive,
+        }
+    }
+
+    /// Adapt the pause to the latency of a write and its failed attempts.
+    pub fn record(&mut self, latency: Duration, failed_attempts: usize) {
+        if !self.is_adaptive {
+            return;
+        }
+        let reason: &str;
+        if failed_attempts > 0 {
+            reason = "sink_error";
+        } else if latency > self.sink_latency_target {
+            reason = "sink_latency";
+        } else {
+            self.delay = self.delay
+                .checked_sub(WRITE_DELAY_STEP)
+                .unwrap_or_default();
+            return;
+        }
+        self.delay = self.delay
+            .div_f64(self.decrease_factor)
+            .max(INITIAL_WRITE_DELAY)
+            .min(MAX_WRITE_DELAY);
+        info!(
+            reason,
+            latency_ms = latency.as_millis() as u64,
+            delay_ms = self.delay.as_millis() as u64,
+            "Back off writes."
+        );
+    }
+
+    /// Time to wait before the next write.
+    pub fn delay(&self) -> Duration {
+        self.delay
+    }
+}
@@ -0,0 +1,640 @@
+use std::{
+    collections::HashMap,
+    env,
+    fmt::Debug,
+    fs,
+    net::SocketAddr,
+    process,
+    str::FromStr,
+    time::Duration,
+};
+
+use serde::Deserialize;
+use solana_sdk::clock::Slot;
+
+use crate::{
+    bigquery::SinkBackend,
+    continuity::ContinuityMode,
+    filter::TransactionFilter,
+    logging::LogFormat,
+    schema::{
+        self,
+        TableDefinition,
+    },
+    storage_write::StreamType,
+    vote::VoteMode,
+};
+
+const ENV_PREFIX: &str = "SOLISTENER_";
+
+/// Settings of the listener.
+/// Defaults are overridden by the TOML config file, which is overridden
+/// by SOLISTENER_* environment variables, which are overridden by the
+/// command line arguments.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub project_id: String,
+    pub dataset_id: String,
+    //RPC nodes, tried in order when one stops responding
+    pub rpc_endpoints: Vec<String>,
+    pub start_slot: Option<Slot>,
+    pub end_slot: Option<Slot>,
+    //Max number of blocks processed concurrently
+    pub max_processor_count: usize,
+    pub concurrency: ConcurrencyConfig,
+    //Distance to keep from the latest finalized slot
+    pub slots_behind_latest: u64,
+    //Max number of slots fetched at once
+    pub max_slot_range: u64,
+    //Number of blocks fetched concurrently ahead of the processing
+    pub prefetch_window: usize,
+    pub votes: VoteMode,
+    //Record every slot as produced or skipped in the slots table
+    pub record_slots: bool,
+    //Check that each block extends the previous one
+    pub continuity: ContinuityMode,
+    pub filter: FilterConfig,
+    pub retry: RetryConfig,
+    pub sink: SinkConfig,
+    pub batch: BatchConfig,
+    pub load_job: LoadJobConfig,
+    pub backfill: BackfillConfig,
+    pub lease: LeaseConfig,
+    pub epochs: EpochsConfig,
+    pub tables: TablesConfig,
+    pub http_address: String,
+    pub staleness_window_secs: u64,
+    pub log_format: LogFormat,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FilterConfig {
+    pub include_programs: Vec<String>,
+    pub exclude_programs: Vec<String>,
+    pub include_accounts: Vec<String>,
+    pub exclude_accounts: Vec<String>,
+}
+
+/// Retry policy of the sink inserts.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RetryConfig {
+    pub max_attempts: u32,
+    pub retry_period_secs: u64,
+    pub timeout_secs: u64,
+}
+
+/// Adaptation of the getBlock requests in flight and of the pace of the writes.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ConcurrencyConfig {
+    //Use the whole prefetch window and write without pause when false
+    pub adaptive: bool,
+    //Requests in flight start at this count and never go below it
+    pub min_request_count: usize,
+    //Slower RPC requests are handled like errors
+    pub rpc_latency_target_ms: u64,
+    //Slower writes of a batch are handled like errors
+    pub sink_latency_target_ms: u64,
+    //Factor applied to the requests in flight and to the write rate on errors
+    pub decrease_factor: f64,
+}
+
+/// Write path of the rows.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SinkConfig {
+    pub backend: SinkBackend,
+    //Only used by the storage_write backend
+    pub stream_type: StreamType,
+}
+
+/// Thresholds of the writer that batches the rows of several blocks.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BatchConfig {
+    //Pending rows are written once any of these is reached
+    pub max_pending_rows: usize,
+    pub max_pending_bytes: usize,
+    pub flush_interval_secs: u64,
+    //Limits of a single insertAll request
+    pub max_request_rows: usize,
+    pub max_request_bytes: usize,
+}
+
+/// Staging of the rows written by the load_job backend.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LoadJobConfig {
+    //Local directory of the chunks waiting to be loaded
+    pub staging_dir: String,
+    //Chunks are uploaded to this Cloud Storage bucket when set,
+    //otherwise they are uploaded with the load job request
+    pub bucket: Option<String>,
+    pub poll_interval_secs: u64,
+    //A load job that is not done after this long is given up on
+    pub job_timeout_secs: u64,
+}
+
+/// Settings of the backfill command.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BackfillConfig {
+    //Number of slot ranges processed concurrently
+    pub shards: u64,
+    //Directory of the progress files of the shards
+    pub progress_dir: String,
+}
+
+/// Coordination of several workers through leases of slot ranges.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LeaseConfig {
+    //Directory of the lease store, shared by the workers.
+    //Workers claim slot ranges from it when set
+    pub dir: Option<String>,
+    //Name of this instance, defaults to the host name and process ID
+    pub owner: Option<String>,
+    //Number of slots of a leased range, counted from the start slot
+    pub range_slots: u64,
+    //Leases that are not renewed for this long are taken over
+    pub ttl_secs: u64,
+    //Number of ranges processed concurrently by this instance
+    pub workers: usize,
+}
+
+/// Settings of the epochs job.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct EpochsConfig {
+    //Time between checks for a completed epoch
+    pub poll_interval_secs: u64,
+    //Stake accounts per getInflationReward request
+    pub reward_batch_size: usize,
+}
+
+/// Settings used by setupbq when it creates the dataset and tables.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TablesConfig {
+    //Location of the dataset, e.g. US or EU
+    pub location: Option<String>,
+    pub labels: HashMap<String, String>,
+    pub partitioning: PartitionType,
+    //Partitions older than this are deleted by BigQuery
+    pub partition_expiration_days: Option<u64>,
+    //Tables partitioned by ingestion time instead of block_timestamp.
+    //Otherwise rows without a block time land in the __NULL__ partition
+    pub ingestion_time_partitioning: Vec<String>,
+    //Clustering columns by table ID, replacing the defaults of the table
+    pub clustering: HashMap<String, Vec<String>>,
+}
+
+/// Granularity of the table partitions.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum PartitionType {
+    Day,
+    //BigQuery allows 4000 partitions per table, about 166 days
+    Hour,
+}
+
+impl Default for PartitionType {
+    fn default() -> PartitionType {
+        PartitionType::Day
+    }
+}
+
+impl PartitionType {
+    pub fn name(&self) -> &'static str {
+        match self {
+            PartitionType::Day => "DAY",
+            PartitionType::Hour => "HOUR",
+        }
+    }
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            project_id: "datadragonio-stage".to_string(),
+            dataset_id: "solana_test".to_string(),
+            rpc_endpoints: vec![
+                "https://api.mainnet-beta.solana.com".to_string(),
+                "https://solana-api.projectserum.com".to_string(),
+            ],
+            start_slot: None,
+            end_slot: None,
+            max_processor_count: num_cpus::get() * 2,
+            concurrency: ConcurrencyConfig::default(),
+            slots_behind_latest: 200,
+            max_slot_range: 100,
+            prefetch_window: 8,
+            votes: VoteMode::Include,
+            record_slots: true,
+            continuity: ContinuityMode::Flag,
+            filter: FilterConfig::default(),
+            retry: RetryConfig::default(),
+            sink: SinkConfig::default(),
+            batch: BatchConfig::default(),
+            load_job: LoadJobConfig::default(),
+            backfill: BackfillConfig::default(),
+            lease: LeaseConfig::default(),
+            epochs: EpochsConfig::default(),
+            tables: TablesConfig::default(),
+            http_address: "0.0.0.0:9090".to_string(),
+            staleness_window_secs: 300,
+            log_format: LogFormat::Text,
+        }
+    }
+}
+
+impl Default for RetryConfig {
+    fn default() -> RetryConfig {
+        RetryConfig {
+            max_attempts: 10,
+            retry_period_secs: 1,
+            timeout_secs: 60,
+        }
+    }
+}
+
+impl Default for ConcurrencyConfig {
+    fn default() -> ConcurrencyConfig {
+        ConcurrencyConfig {
+            adaptive: true,
+            min_request_count: 1,
+            rpc_latency_target_ms: 2000,
+            sink_latency_target_ms: 30_000,
+            decrease_factor: 0.5,
+        }
+    }
+}
+
+impl Default for SinkConfig {
+    fn default() -> SinkConfig {
+        SinkConfig {
+            backend: SinkBackend::InsertAll,
+            stream_type: StreamType::Committed,
+        }
+    }
+}
+
+impl Default for BatchConfig {
+    fn default() -> BatchConfig {
+        BatchConfig {
+            max_pending_rows: 20_000,
+            max_pending_bytes: 50 * 1024 * 1024,
+            flush_interval_secs: 5,
+            //insertAll accepts 10MB and recommends 500 rows per request
+            max_request_rows: 500,
+            max_request_bytes: 9 * 1024 * 1024,
+        }
+    }
+}
+
+impl Default for BackfillConfig {
+    fn default() -> BackfillConfig {
+        BackfillConfig {
+            shards: 4,
+            progress_dir: "backfill".to_string(),
+        }
+    }
+}
+
+impl Default for LeaseConfig {
+    fn default() -> LeaseConfig {
+        LeaseConfig {
+            dir: None,
+            owner: None,
+            range_slots: 10_000,
+            ttl_secs: 300,
+            workers: 1,
+        }
+    }
+}
+
+impl Default for EpochsConfig {
+    fn default() -> EpochsConfig {
+        EpochsConfig {
+            poll_interval_secs: 60,
+            reward_batch_size: 1000,
+        }
+    }
+}
+
+impl Default for LoadJobConfig {
+    fn default() -> LoadJobConfig {
+        LoadJobConfig {
+            staging_dir: "staging".to_string(),
+            bucket: None,
+            poll_interval_secs: 5,
+            job_timeout_secs: 3600,
+        }
+    }
+}
+
+impl EpochsConfig {
+    pub fn poll_interval(&self) -> Duration {
+        Duration::from_secs(self.poll_interval_secs)
+    }
+}
+
+impl LoadJobConfig {
+    pub fn poll_interval(&self) -> Duration {
+        Duration::from_secs(self.poll_interval_secs)
+    }
+
+    pub fn job_timeout(&self) -> Duration {
+        Duration::from_secs(self.job_timeout_secs)
+    }
+}
+
+impl LeaseConfig {
+    pub fn ttl(&self) -> Duration {
+        Duration::from_secs(self.ttl_secs)
+    }
+
+    pub fn owner(&self) -> String {
+        match &self.owner {
+            Some(owner) => owner.clone(),
+            None => {
+                let host = env::var("HOSTNAME")
+                    .unwrap_or_else(|_| "solistener".to_string());
+                format!("{}-{}", host, process::id())
+            }
+        }
+    }
+}
+
+impl ConcurrencyConfig {
+    pub fn rpc_latency_target(&self) -> Duration {
+        Duration::from_millis(self.rpc_latency_target_ms)
+    }
+
+    pub fn sink_latency_target(&self) -> Duration {
+        Duration::from_millis(self.sink_latency_target_ms)
+    }
+}
+
+impl BatchConfig {
+    pub fn flush_interval(&self) -> Duration {
+        Duration::from_secs(self.flush_interval_secs)
+    }
+}
+
+impl RetryConfig {
+    pub fn retry_period(&self) -> Duration {
+        Duration::from_secs(self.retry_period_secs)
+    }
+
+    pub fn timeout(&self) -> Duration {
+        Duration::from_secs(self.timeout_secs)
+    }
+}
+
+fn env_value<T>(name: &str) -> Result<Option<T>, String>
+where
+    T: FromStr,
+    T::Err: Debug,
+{
+    let key = format!("{}{}", ENV_PREFIX, name);
+    match env::var(&key) {
+        Err(_) => Ok(None),
+        Ok(value) => {
+            let parsed = value
+                .parse()
+                .map_err(|err| format!("Invalid value for {}: {:?}", key, err))?;
+            Ok(Some(parsed))
+        }
+    }
+}
+
+fn env_override<T>(name: &str, target: &mut T) -> Result<(), String>
+where
+    T: FromStr,
+    T::Err: Debug,
+{
+    if let Some(value) = env_value(name)? {
+        *target = value;
+    }
+    Ok(())
+}
+
+fn env_override_list(name: &str, target: &mut Vec<String>) -> Result<(), String> {
+    if let Some(value) = env_value::<String>(name)? {
+        *target = value
+            .split(',')
+            .map(|item| item.trim().to_string())
+            .filter(|item| !item.is_empty())
+            .collect();
+    }
+    Ok(())
+}
+
+impl Config {
+    /// Load the config file, if any, on top of the defaults
+    /// and apply the environment variable overrides.
+    pub fn load(path: Option<&str>) -> Result<Config, String> {
+        let mut config: Config;
+        match path {
+            None => {
+                config = Config::default();
+            }
+            Some(path) => {
+                let contents = fs::read_to_string(path)
+                    .map_err(|err| format!("Failed to read config file {}: {}", path, err))?;
+                config = toml::from_str(&contents)
+                    .map_err(|err| format!("Failed to parse config file {}: {}", path, err))?;
+            }
+        }
+        config.apply_env()?;
+        Ok(config)
+    }
+
+    fn apply_env(&mut self) -> Result<(), String> {
+        env_override("PROJECT", &mut self.project_id)?;
+        env_override("DATASET", &mut self.dataset_id)?;
+        env_override_list("RPC_ENDPOINTS", &mut self.rpc_endpoints)?;
+        if let Some(slot) = env_value("START_SLOT")? {
+            self.start_slot = Some(slot);
+        }
+        if let Some(slot) = env_value("END_SLOT")? {
+            self.end_slot = Some(slot);
+        }
+        env_override("MAX_PROCESSOR_COUNT", &mut self.max_processor_count)?;
+        env_override("CONCURRENCY_ADAPTIVE", &mut self.concurrency.adaptive)?;
+        env_override("CONCURRENCY_MIN_REQUEST_COUNT", &mut self.concurrency.min_request_count)?;
+        env_override("SLOTS_BEHIND_LATEST", &mut self.slots_behind_latest)?;
+        env_override("MAX_SLOT_RANGE", &mut self.max_slot_range)?;
+        env_override("PREFETCH_WINDOW", &mut self.prefetch_window)?;
+        env_override("VOTES", &mut self.votes)?;
+        env_override("RECORD_SLOTS", &mut self.record_slots)?;
+        env_override("CONTINUITY", &mut self.continuity)?;
+        env_override_list("INCLUDE_PROGRAMS", &mut self.filter.include_programs)?;
+        env_override_list("EXCLUDE_PROGRAMS", &mut self.filter.exclude_programs)?;
+        env_override_list("INCLUDE_ACCOUNTS", &mut self.filter.include_accounts)?;
+        env_override_list("EXCLUDE_ACCOUNTS", &mut self.filter.exclude_accounts)?;
+        env_override("RETRY_MAX_ATTEMPTS", &mut self.retry.max_attempts)?;
+        env_override("RETRY_PERIOD_SECS", &mut self.retry.retry_period_secs)?;
+        env_override("RETRY_TIMEOUT_SECS", &mut self.retry.timeout_secs)?;
+        env_override("SINK_BACKEND", &mut self.sink.backend)?;
+        env_override("SINK_STREAM_TYPE", &mut self.sink.stream_type)?;
+        env_override("BATCH_MAX_PENDING_ROWS", &mut self.batch.max_pending_rows)?;
+        env_override("BATCH_MAX_PENDING_BYTES", &mut self.batch.max_pending_bytes)?;
+        env_override("BATCH_FLUSH_INTERVAL_SECS", &mut self.batch.flush_interval_secs)?;
+        env_override("LOAD_JOB_STAGING_DIR", &mut self.load_job.staging_dir)?;
+        env_override("LOAD_JOB_TIMEOUT_SECS", &mut self.load_job.job_timeout_secs)?;
+        env_override("BACKFILL_SHARDS", &mut self.backfill.shards)?;
+        env_override("BACKFILL_PROGRESS_DIR", &mut self.backfill.progress_dir)?;
+        if let Some(bucket) = env_value("LOAD_JOB_BUCKET")? {
+            self.load_job.bucket = Some(bucket);
+        }
+        if let Some(dir) = env_value("LEASE_DIR")? {
+            self.lease.dir = Some(dir);
+        }
+        if let Some(owner) = env_value("LEASE_OWNER")? {
+            self.lease.owner = Some(owner);
+        }
+        env_override("LEASE_RANGE_SLOTS", &mut self.lease.range_slots)?;
+        env_override("LEASE_TTL_SECS", &mut self.lease.ttl_secs)?;
+        env_override("LEASE_WORKERS", &mut self.lease.workers)?;
+        env_override("EPOCHS_POLL_INTERVAL_SECS", &mut self.epochs.poll_interval_secs)?;
+        env_override("EPOCHS_REWARD_BATCH_SIZE", &mut self.epochs.reward_batch_size)?;
+        env_override("HTTP_ADDRESS", &mut self.http_address)?;
+        env_override("STALENESS_WINDOW_SECS", &mut self.staleness_window_secs)?;
+        env_override("LOG_FORMAT", &mut self.log_format)?;
+        Ok(())
+    }
+
+    /// Check the settings once all overrides are applied.
+    pub fn validate(&self) -> Result<(), String> {
+        if self.project_id.is_empty() {
+            return Err("Project ID is required".to_string());
+        }
+        if self.dataset_id.is_empty() {
+            return Err("Dataset ID is required".to_string());
+        }
+        if self.rpc_endpoints.is_empty() {
+            return Err("At least one RPC endpoint is required".to_string());
+        }
+        if let (Some(start), Some(end)) = (self.start_slot, self.end_slot) {
+            if start > end {
+                return Err(format!("Start slot {} is after end slot {}", start, end));
+            }
+        }
+        if self.start_slot == Some(0) {
+            return Err("Start slot must be above 0".to_string());
+        }
+        if self.max_processor_count == 0 {
+            return Err("Max processor count must be above 0".to_string());
+        }
+        if self.concurrency.min_request_count == 0 {
+            return Err("Min request count must be above 0".to_string());
+        }
+        if self.concurrency.decrease_factor <= 0.0 || self.concurrency.decrease_factor >= 1.0 {
+            return Err("Concurrency decrease factor must be between 0 and 1".to_string());
+        }
+        if self.max_slot_range == 0 {
+            return Err("Max slot range must be above 0".to_string());
+        }
+        if self.prefetch_window == 0 {
+            return Err("Prefetch window must be above 0".to_string());
+        }
+        if self.retry.max_attempts == 0 {
+            return Err("Retry max attempts must be above 0".to_string());
+        }
+        if self.batch.max_request_rows == 0 || self.batch.max_request_bytes == 0 {
+            return Err("Batch request limits must be above 0".to_string());
+        }
+        if self.backfill.shards == 0 {
+            return Err("Backfill shards must be above 0".to_string());
+        }
+        if self.epochs.reward_batch_size == 0 {
+            return Err("Epochs reward batch size must be above 0".to_string());
+        }
+        if self.lease.dir.is_some() {
+            if self.lease.range_slots == 0 || self.lease.ttl_secs == 0 || self.lease.workers == 0 {
+                return Err("Lease range slots, TTL and workers must be above 0".to_string());
+            }
+            //Every worker must split the slots in the same ranges
+            if self.start_slot.is_none() {
+                return Err("Start slot is required when workers claim leases".to_string());
+            }
+        }
+        if self.sink.backend == SinkBackend::LoadJob && self.load_job.staging_dir.is_empty() {
+            return Err("Staging directory is required by the load_job backend".to_string());
+        }
+        if self.sink.backend == SinkBackend::LoadJob && self.load_job.job_timeout_secs == 0 {
+            return Err("Load job timeout must be above 0".to_string());
+        }
+        self.http_address()?;
+        self.transaction_filter()?;
+        self.tables.validate()?;
+        Ok(())
+    }
+
+    pub fn http_address(&self) -> Result<SocketAddr, String> {
+        self.http_address
+            .parse()
+            .map_err(|_| format!("Invalid HTTP address {}", self.http_address))
+    }
+
+    pub fn staleness_window(&self) -> Duration {
+        Duration::from_secs(self.staleness_window_secs)
+    }
+
+    pub fn transaction_filter(&self) -> Result<TransactionFilter, String> {
+        TransactionFilter::new(
+            &self.filter.include_programs,
+            &self.filter.exclude_programs,
+            &self.filter.include_accounts,
+            &self.filter.exclude_accounts,
+        )
+    }
+}
+
+impl TablesConfig {
+    /// Clustering columns of a table, from the config or the table defaults.
+    pub fn clustering(&self, definition: &TableDefinition) -> Vec<String> {
+        match self.clustering.get(definition.table_id) {
+            Some(fields) => fields.clone(),
+            None => definition.clustering.iter().map(|field| field.to_string()).collect(),
+        }
+    }
+
+    /// Column the table is partitioned by, None for ingestion time.
+    pub fn partition_field(&self, definition: &TableDefinition) -> Option<&'static str> {
+        if self.ingestion_time_partitioning.iter().any(|table_id| table_id == definition.table_id) {
+            return None;
+        }
+        return Some(definition.partition_field);
+    }
+
+    pub fn partition_expiration(&self) -> Option<Duration> {
+        self.partition_expiration_days
+            .map(|days| Duration::from_secs(days * 24 * 60 * 60))
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        let tables = schema::tables();
+        let table_ids: Vec<&String> = self.clustering
+            .keys()
+            .chain(self.ingestion_time_partitioning.iter())
+            .collect();
+        for table_id in table_ids {
+            if !tables.iter().any(|definition| definition.table_id == table_id) {
+                return Err(format!("Unknown table {}", table_id));
+            }
+        }
+        for definition in &tables {
+            schema::validate_clustering(definition, &self.clustering(definition))?;
+        }
+        if self.partition_expiration_days == Some(0) {
+            return Err("Partition expiration must be above 0 days".to_string());
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,119 @@
+use std::{
+    str::FromStr,
+    sync::atomic::{
+        AtomicBool,
+        Ordering,
+    },
+};
+
+use serde::Deserialize;
+use solana_sdk::clock::Slot;
+use solana_transaction_status::EncodedConfirmedBlock;
+use tracing::error;
+
+use crate::metrics::BLOCKHASH_MISMATCHES;
+
+//Set once a listener halts, so that the other shards of the process stop too
+static HALTED: AtomicBool = AtomicBool::new(false);
+
+/// Whether a listener of the process halted on a broken blockhash chain.
+pub fn is_halted() -> bool {
+    HALTED.load(Ordering::SeqCst)
+}
+
+/// What to do when a block does not extend the previous block.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ContinuityMode {
+    //Do not check the blockhash chain
+    Off,
+    //Log and count the mismatch, and record it in the blocks table
+    Flag,
+    //Stop the listeners before the block is recorded
+    Halt,
+}
+
+impl FromStr for ContinuityMode {
+    type Err = String;
+
+    fn from_str(mode: &str) -> Result<ContinuityMode, String> {
+        match mode {
+            "off" => Ok(ContinuityMode::Off),
+            "flag" => Ok(ContinuityMode::Flag),
+            "halt" => Ok(ContinuityMode::Halt),
+            _ => Err(format!("Unknown continuity mode {}", mode)),
+        }
+    }
+}
+
+struct PreviousBlock {
+    slot: Slot,
+    blockhash: String,
+    //Set when every block since this one is seen by the validator.
+    //Blocks read back from the sink may be followed by unrecorded blocks.
+    is_followed: bool,
+}
+
+/// Checks that the blocks, in slot order, form a single chain: the
+/// previous blockhash of each block is the hash of its parent block.
+pub struct ContinuityValidator {
+    mode: ContinuityMode,
+    previous_block: Option<PreviousBlock>,
+}
+
+impl ContinuityValidator {
+    /// The validator may start from the latest stored block.
+    pub fn new(mode: ContinuityMode, stored_block: Option<(Slot, String)>) -> ContinuityValidator {
+        ContinuityValidator {
+            mode: mode,
+            previous_block: stored_block.map(|(slot, blockhash)| PreviousBlock {
+                slot: slot,
+                blockhash: blockhash,
+                is_followed: false,
+            }),
+        }
+    }
+
+    /// Check the block against the previous one. Returns whether it extends
+    /// it, None if unknown. Fails on a mismatch in halt mode, or once any
+    /// listener of the process halted.
+    pub fn check(&mut self, slot: Slot, block: &EncodedConfirmedBlock) -> Result<Option<bool>, String> {
+        if self.mode == ContinuityMode::Off {
+            return Ok(None);
+        }
+        if is_halted() {
+            return Err("Another listener halted on a broken blockhash chain".to_string());
+        }
+        let mut is_consistent: Option<bool> = None;
+        if let Some(previous_block) = &self.previous_block {
+            if block.parent_slot == previous_block.slot {
+                is_consistent = Some(block.previous_blockhash == previous_block.blockhash);
+            } else if previous_block.is_followed {
+                //The parent is not the previous block, which is on another fork
+                //or the parent block is missing
+                is_consistent = Some(false);
+            }
+            if is_consistent == Some(false) {
+                BLOCKHASH_MISMATCHES.inc();
+                error!(
+                    slot,
+                    parent_slot = block.parent_slot,
+                    previous_blockhash = block.previous_blockhash.as_str(),
+                    expected_slot = previous_block.slot,
+                    expected_blockhash = previous_block.blockhash.as_str(),
+                    "Block does not extend the previous block."
+                );
+                if self.mode == ContinuityMode::Halt {
+                    HALTED.store(true, Ordering::SeqCst);
+                    return Err(format!("Blockhash chain is broken at slot {}", slot));
+                }
+            }
+        }
+        self.previous_block = Some(PreviousBlock {
+            slot: slot,
+            blockhash: block.blockhash.clone(),
+            is_followed: true,
+        });
+        return Ok(is_consistent);
+    }
+}
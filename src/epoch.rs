@@ -0,0 +1,211 @@
+use std::{
+    collections::HashMap,
+    str::FromStr,
+    thread,
+};
+
+use chrono::{
+    DateTime,
+    Utc,
+};
+use serde::Serialize;
+use solana_client::rpc_response::RpcInflationRate;
+use solana_sdk::{
+    clock::{
+        Epoch,
+        Slot,
+    },
+    pubkey::Pubkey,
+};
+use tracing::info;
+
+use crate::{
+    bigquery::BigQuery,
+    bigquery_record,
+    block,
+    config::Config,
+    health::HEALTH,
+    schema::{
+        EPOCHS_TABLE_ID,
+        INFLATION_REWARDS_TABLE_ID,
+    },
+    solana_rpc::SolanaRpc,
+};
+
+const STAKE_PROGRAM_ID: &str = "Stake11111111111111111111111111111111111111";
+
+bigquery_record! {
+    /// A completed epoch, with the inflation rate observed while it was
+    /// the current epoch.
+    #[derive(Serialize)]
+    pub struct EpochSummary {
+        //Time of the block that paid the rewards of the epoch
+        block_timestamp: Option<DateTime<Utc>>,
+        epoch: u64,
+        first_slot: u64,
+        last_slot: u64,
+        slot_count: u64,
+        //Unknown for epochs that completed before the job observed them
+        inflation_total: Option<f64>,
+        inflation_validator: Option<f64>,
+        inflation_foundation: Option<f64>,
+        reward_count: u64,
+        total_reward: u64,
+    }
+}
+
+bigquery_record! {
+    /// Inflation reward paid to a stake account at the end of an epoch.
+    #[derive(Serialize)]
+    pub struct InflationReward {
+        block_timestamp: Option<DateTime<Utc>>,
+        epoch: u64,
+        stake_account: String,
+        amount: u64,
+        post_balance: u64,
+        //Slot of the block that paid the reward
+        effective_slot: u64,
+        commission: Option<u8>,
+    }
+}
+
+/// Records completed epochs and the inflation rewards of every stake account.
+pub struct EpochRecorder {
+    config: Config,
+    solana_client: SolanaRpc,
+    bq_client: BigQuery,
+    //Rates observed while their epoch was the current one
+    inflation_rates: HashMap<Epoch, RpcInflationRate>,
+}
+
+impl EpochRecorder {
+    pub fn new(config: &Config) -> Result<EpochRecorder, String> {
+        return Ok(EpochRecorder {
+            config: config.clone(),
+            solana_client: SolanaRpc::new(&config.rpc_endpoints),
+            bq_client: BigQuery::new(config)?,
+            inflation_rates: HashMap::new(),
+        });
+    }
+
+    fn get_rewards(&mut self, epoch: Epoch) -> Vec<InflationReward> {
+        let stake_program_id = Pubkey::from_str(STAKE_PROGRAM_ID).unwrap();
+        let stake_accounts = self.solana_client
+            .get_program_account_addresses_with_retry(&stake_program_id);
+        info!(epoch, stake_accounts = stake_accounts.len(), "Fetch inflation rewards.");
+
+        let mut rewards: Vec<InflationReward> = Vec::new();
+        //Rewards of an epoch are all paid by the same block
+        let mut block_timestamps: HashMap<Slot, Option<DateTime<Utc>>> = HashMap::new();
+        for addresses in stake_accounts.chunks(self.config.epochs.reward_batch_size) {
+            let batch = self.solana_client.get_inflation_reward_with_retry(addresses, epoch);
+            for (address, reward) in addresses.iter().zip(batch) {
+                let reward = match reward {
+                    Some(reward) => reward,
+                    None => continue,
+                };
+                let block_timestamp: Option<DateTime<Utc>>;
+                match block_timestamps.get(&reward.effective_slot) {
+                    Some(timestamp) => {
+                        block_timestamp = *timestamp;
+                    }
+                    None => {
+                        let block_time = self.solana_client
+                            .get_block_time_with_retry(reward.effective_slot);
+                        block_timestamp = block::block_timestamp(Some(block_time));
+                        block_timestamps.insert(reward.effective_slot, block_timestamp);
+                    }
+                }
+                rewards.push(InflationReward {
+                    block_timestamp: block_timestamp,
+                    epoch: epoch,
+                    stake_account: address.to_string(),
+                    amount: reward.amount,
+                    post_balance: reward.post_balance,
+                    effective_slot: reward.effective_slot,
+                    commission: reward.commission,
+                });
+            }
+        }
+        return rewards;
+    }
+
+    /// Record the epoch and the inflation rewards paid at its end.
+    /// Returns false if the rows could not be written.
+    pub fn record_epoch(&mut self, epoch: Epoch) -> bool {
+        let epoch_schedule = self.solana_client.get_epoch_schedule_with_retry();
+        let rewards = self.get_rewards(epoch);
+        let rate = self.inflation_rates.get(&epoch);
+        let summary = EpochSummary {
+            block_timestamp: rewards.first().and_then(|reward| reward.block_timestamp),
+            epoch: epoch,
+            first_slot: epoch_schedule.get_first_slot_in_epoch(epoch),
+            last_slot: epoch_schedule.get_last_slot_in_epoch(epoch),
+            slot_count: epoch_schedule.get_slots_in_epoch(epoch),
+            inflation_total: rate.map(|rate| rate.total),
+            inflation_validator: rate.map(|rate| rate.validator),
+            inflation_foundation: rate.map(|rate| rate.foundation),
+            reward_count: rewards.len() as u64,
+            total_reward: rewards.iter().map(|reward| reward.amount).sum(),
+        };
+        //The epoch is written last, it is where the job resumes.
+        //Rewards written by a failed attempt are dropped by their insert ID.
+        let reward_id = |reward: &InflationReward| format!("{}-{}", reward.epoch, reward.stake_account);
+        if !self.bq_client.insert_with_ids(INFLATION_REWARDS_TABLE_ID, &rewards, reward_id) {
+            return false;
+        }
+        if !self.bq_client.insert(EPOCHS_TABLE_ID, &[summary]) {
+            return false;
+        }
+        //Kept until the epoch is written so that a retry still has it
+        self.inflation_rates.remove(&epoch);
+        info!(epoch, rewards = rewards.len(), "Epoch recorded.");
+        return true;
+    }
+
+    /// Record every epoch as it completes, starting after the latest
+    /// recorded epoch, or with the last completed epoch.
+    pub fn watch(&mut self) {
+        let poll_interval = self.config.epochs.poll_interval();
+        let mut next_epoch: Option<Epoch> = self.bq_client
+            .get_latest_epoch()
+            .ok()
+            .map(|epoch| epoch + 1);
+        loop {
+            let rate = self.solana_client.get_inflation_rate_with_retry();
+            let current_epoch = rate.epoch;
+            self.inflation_rates.insert(rate.epoch, rate);
+
+            if current_epoch > 0 {
+                let last_completed = current_epoch - 1;
+                let first_epoch = next_epoch.unwrap_or(last_completed);
+                for epoch in first_epoch..=last_completed {
+                    if !self.record_epoch(epoch) {
+                        //Retried on the next poll
+                        break;
+                    }
+                    next_epoch = Some(epoch + 1);
+                }
+            }
+            //Epochs only complete every few days, polling is the progress
+            HEALTH.record_progress();
+            thread::sleep(poll_interval);
+        }
+    }
+}
+
+/// Record a single epoch if one is set, otherwise every epoch as it completes.
+pub fn run(config: &Config, epoch: Option<Epoch>) -> Result<(), String> {
+    let mut recorder = EpochRecorder::new(config)?;
+    match epoch {
+        Some(epoch) => {
+            if !recorder.record_epoch(epoch) {
+                return Err(format!("Failed to record epoch {}", epoch));
+            }
+        }
+        None => {
+            recorder.watch();
+        }
+    }
+    return Ok(());
+}
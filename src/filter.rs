@@ -0,0 +1,95 @@
+use std::{
+    collections::HashSet,
+    str::FromStr,
+};
+
+use solana_sdk::{
+    pubkey::Pubkey,
+    transaction::Transaction as SolanaTransaction,
+};
+use solana_transaction_status::{
+    UiInstruction,
+    UiTransactionStatusMeta,
+};
+
+/// Selects the transactions that are ingested by the program IDs
+/// they invoke and the account addresses they reference.
+#[derive(Clone, Default)]
+pub struct TransactionFilter {
+    include_programs: HashSet<Pubkey>,
+    exclude_programs: HashSet<Pubkey>,
+    include_accounts: HashSet<Pubkey>,
+    exclude_accounts: HashSet<Pubkey>,
+}
+
+fn parse_addresses(addresses: &[String]) -> Result<HashSet<Pubkey>, String> {
+    let mut pubkeys = HashSet::new();
+    for address in addresses {
+        let pubkey = Pubkey::from_str(address)
+            .map_err(|_| format!("Invalid address {}", address))?;
+        pubkeys.insert(pubkey);
+    }
+    return Ok(pubkeys);
+}
+
+impl TransactionFilter {
+    pub fn new(
+        include_programs: &[String],
+        exclude_programs: &[String],
+        include_accounts: &[String],
+        exclude_accounts: &[String],
+    ) -> Result<TransactionFilter, String> {
+        Ok(TransactionFilter {
+            include_programs: parse_addresses(include_programs)?,
+            exclude_programs: parse_addresses(exclude_programs)?,
+            include_accounts: parse_addresses(include_accounts)?,
+            exclude_accounts: parse_addresses(exclude_accounts)?,
+        })
+    }
+
+    fn has_includes(&self) -> bool {
+        !self.include_programs.is_empty() || !self.include_accounts.is_empty()
+    }
+
+    /// Whether a transaction passes the filter.
+    /// Excludes take precedence over includes. Without any include
+    /// every transaction that is not excluded passes.
+    pub fn is_match(
+        &self,
+        meta: &UiTransactionStatusMeta,
+        solana_transaction: &SolanaTransaction,
+    ) -> bool {
+        let account_keys = &solana_transaction.message.account_keys;
+
+        //Programs invoked by the instructions and their inner instructions
+        let mut program_ids: HashSet<&Pubkey> = HashSet::new();
+        for instruction in &solana_transaction.message.instructions {
+            if let Some(program_id) = account_keys.get(instruction.program_id_index as usize) {
+                program_ids.insert(program_id);
+            }
+        }
+        if let Some(inner_instructions) = &meta.inner_instructions {
+            for inner in inner_instructions {
+                for instruction in &inner.instructions {
+                    if let UiInstruction::Compiled(compiled) = instruction {
+                        if let Some(program_id) = account_keys.get(compiled.program_id_index as usize) {
+                            program_ids.insert(program_id);
+                        }
+                    }
+                }
+            }
+        }
+
+        if program_ids.iter().any(|id| self.exclude_programs.contains(id)) {
+            return false;
+        }
+        if account_keys.iter().any(|key| self.exclude_accounts.contains(key)) {
+            return false;
+        }
+        if !self.has_includes() {
+            return true;
+        }
+        return program_ids.iter().any(|id| self.include_programs.contains(id))
+            || account_keys.iter().any(|key| self.include_accounts.contains(key));
+    }
+}
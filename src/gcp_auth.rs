@@ -0,0 +1,32 @@
+use hyper::client::HttpConnector;
+use hyper_rustls::HttpsConnector;
+use yup_oauth2::{
+    authenticator::Authenticator,
+    ServiceAccountAuthenticator,
+};
+
+//Covers BigQuery and Cloud Storage
+const SCOPE: &str = "https://www.googleapis.com/auth/cloud-platform";
+
+pub type GcpAuthenticator = Authenticator<HttpsConnector<HttpConnector>>;
+
+/// Authenticator of the service account in the given key file.
+pub async fn authenticator(gcp_key: &str) -> Result<GcpAuthenticator, String> {
+    let key = yup_oauth2::read_service_account_key(gcp_key)
+        .await
+        .map_err(|err| format!("Failed to read service account key: {}", err))?;
+    let authenticator = ServiceAccountAuthenticator::builder(key)
+        .build()
+        .await
+        .map_err(|err| format!("Failed to build authenticator: {}", err))?;
+    return Ok(authenticator);
+}
+
+/// Access token for the Google APIs, refreshed when it expires.
+pub async fn access_token(authenticator: &GcpAuthenticator) -> Result<String, String> {
+    let token = authenticator
+        .token(&[SCOPE])
+        .await
+        .map_err(|err| format!("Failed to get access token: {}", err))?;
+    return Ok(token.as_str().to_string());
+}
@@ -0,0 +1,84 @@
+use std::{
+    sync::{
+        atomic::{
+            AtomicBool,
+            Ordering,
+        },
+        Mutex,
+    },
+    time::{
+        Duration,
+        Instant,
+    },
+};
+
+use lazy_static::lazy_static;
+
+const DEFAULT_STALENESS_WINDOW: Duration = Duration::from_secs(300);
+
+/// Health of the listener as reported by the /healthz and /readyz endpoints.
+pub struct Health {
+    rpc_reachable: AtomicBool,
+    sink_reachable: AtomicBool,
+    last_progress: Mutex<Instant>,
+    staleness_window: Mutex<Duration>,
+}
+
+lazy_static! {
+    pub static ref HEALTH: Health = Health::new();
+}
+
+impl Health {
+    fn new() -> Health {
+        Health {
+            rpc_reachable: AtomicBool::new(false),
+            sink_reachable: AtomicBool::new(false),
+            last_progress: Mutex::new(Instant::now()),
+            staleness_window: Mutex::new(DEFAULT_STALENESS_WINDOW),
+        }
+    }
+
+    /// Max time the processed slot may stand still before
+    /// the listener is considered stuck.
+    pub fn set_staleness_window(&self, window: Duration) {
+        *self.staleness_window.lock().unwrap() = window;
+    }
+
+    pub fn set_rpc_reachable(&self, reachable: bool) {
+        self.rpc_reachable.store(reachable, Ordering::Relaxed);
+    }
+
+    pub fn set_sink_reachable(&self, reachable: bool) {
+        self.sink_reachable.store(reachable, Ordering::Relaxed);
+    }
+
+    /// Record that the processed slot advanced.
+    pub fn record_progress(&self) {
+        *self.last_progress.lock().unwrap() = Instant::now();
+    }
+
+    pub fn is_stale(&self) -> bool {
+        let window = *self.staleness_window.lock().unwrap();
+        return self.last_progress.lock().unwrap().elapsed() > window;
+    }
+
+    /// Live as long as the processed slot keeps advancing.
+    pub fn check_live(&self) -> Result<(), String> {
+        if self.is_stale() {
+            return Err("Processed slot is not advancing".to_string());
+        }
+        return Ok(());
+    }
+
+    /// Ready when live and both the RPC node and the sink are reachable.
+    pub fn check_ready(&self) -> Result<(), String> {
+        self.check_live()?;
+        if !self.rpc_reachable.load(Ordering::Relaxed) {
+            return Err("RPC node is not reachable".to_string());
+        }
+        if !self.sink_reachable.load(Ordering::Relaxed) {
+            return Err("Sink is not reachable".to_string());
+        }
+        return Ok(());
+    }
+}
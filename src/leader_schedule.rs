@@ -0,0 +1,90 @@
+use std::collections::HashMap;
+
+use solana_sdk::{
+    clock::{
+        Epoch,
+        Slot,
+    },
+    epoch_schedule::EpochSchedule,
+};
+use tracing::info;
+
+use crate::solana_rpc::SolanaRpc;
+
+//Epochs kept in the cache, enough for a range that crosses an epoch boundary
+const MAX_CACHED_EPOCHS: usize = 3;
+
+// Leaders of the slots of an epoch, as indexes into the validator identities
+struct EpochLeaders {
+    identities: Vec<String>,
+    slot_leaders: Vec<u32>,
+}
+
+/// Cache of the leader schedule of the recent epochs, fetched once per
+/// epoch, used to attribute each slot to the validator that produces it.
+pub struct LeaderSchedule {
+    epoch_schedule: Option<EpochSchedule>,
+    //None when the node no longer has the schedule of the epoch
+    epochs: HashMap<Epoch, Option<EpochLeaders>>,
+}
+
+impl LeaderSchedule {
+    pub fn new() -> LeaderSchedule {
+        LeaderSchedule {
+            epoch_schedule: None,
+            epochs: HashMap::new(),
+        }
+    }
+
+    fn get_epoch_schedule(&mut self, solana_client: &mut SolanaRpc) -> EpochSchedule {
+        if self.epoch_schedule.is_none() {
+            self.epoch_schedule = Some(solana_client.get_epoch_schedule_with_retry());
+        }
+        return self.epoch_schedule.clone().unwrap();
+    }
+
+    // Fetch the leaders of the epoch, None if the node does not have them.
+    // Failed requests are retried, so that a leader is never left empty
+    // because of a transient error.
+    fn fetch_epoch(solana_client: &mut SolanaRpc, first_slot: Slot, slot_count: u64) -> Option<EpochLeaders> {
+        let schedule = solana_client.get_leader_schedule_with_retry(first_slot)?;
+        let mut epoch_leaders = EpochLeaders {
+            identities: Vec::new(),
+            slot_leaders: vec![u32::MAX; slot_count as usize],
+        };
+        for (identity, slot_indexes) in schedule {
+            let identity_index = epoch_leaders.identities.len() as u32;
+            epoch_leaders.identities.push(identity);
+            for slot_index in slot_indexes {
+                if let Some(leader) = epoch_leaders.slot_leaders.get_mut(slot_index) {
+                    *leader = identity_index;
+                }
+            }
+        }
+        return Some(epoch_leaders);
+    }
+
+    /// Identity of the validator scheduled to produce the block of the slot.
+    pub fn get_leader(&mut self, solana_client: &mut SolanaRpc, slot: Slot) -> Option<String> {
+        let epoch_schedule = self.get_epoch_schedule(solana_client);
+        let epoch = epoch_schedule.get_epoch(slot);
+        let first_slot = epoch_schedule.get_first_slot_in_epoch(epoch);
+        if !self.epochs.contains_key(&epoch) {
+            let slot_count = epoch_schedule.get_slots_in_epoch(epoch);
+            let epoch_leaders = Self::fetch_epoch(solana_client, first_slot, slot_count);
+            if epoch_leaders.is_none() {
+                info!(epoch, "Leader schedule of the epoch is not available.");
+            }
+            if self.epochs.len() >= MAX_CACHED_EPOCHS {
+                let oldest = self.epochs.keys().min().copied();
+                if let Some(oldest) = oldest {
+                    self.epochs.remove(&oldest);
+                }
+            }
+            self.epochs.insert(epoch, epoch_leaders);
+        }
+        let epoch_leaders = self.epochs.get(&epoch)?.as_ref()?;
+        let leader_index = *epoch_leaders.slot_leaders.get((slot - first_slot) as usize)?;
+        return epoch_leaders.identities.get(leader_index as usize).cloned();
+    }
+}
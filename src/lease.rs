@@ -0,0 +1,465 @@
+use std::{
+    fs,
+    io::ErrorKind,
+    path::{
+        Path,
+        PathBuf,
+    },
+    process,
+    sync::{
+        atomic::{
+            AtomicBool,
+            AtomicU64,
+            Ordering,
+        },
+        mpsc::{
+            self,
+            RecvTimeoutError,
+            Sender,
+        },
+        Arc,
+    },
+    thread,
+    time::{
+        Duration,
+        SystemTime,
+        UNIX_EPOCH,
+    },
+};
+
+use serde::{
+    Deserialize,
+    Serialize,
+};
+use solana_sdk::clock::Slot;
+use tracing::{
+    info,
+    info_span,
+    warn,
+};
+
+use crate::{
+    backfill::ShardProgress,
+    block_listener::Listener,
+    config::Config,
+    solana_rpc::SolanaRpc,
+};
+
+//A lock older than this was left by a crashed worker
+const STALE_LOCK: Duration = Duration::from_secs(30);
+const LOCK_WAIT: Duration = Duration::from_millis(50);
+//Wait before looking again for a range to claim
+const IDLE_WAIT: Duration = Duration::from_secs(5);
+
+//Locks taken by this process, part of the lock tokens
+static LOCK_COUNT: AtomicU64 = AtomicU64::new(0);
+
+/// Claim of a slot range by a worker, valid until it expires.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Lease {
+    pub first_slot: Slot,
+    pub last_slot: Slot,
+    pub owner: String,
+    //Unix time in seconds
+    pub expires_at: u64,
+    //Latest slot whose rows are written
+    pub processed_slot: Option<Slot>,
+}
+
+impl Lease {
+    pub fn is_done(&self) -> bool {
+        match self.processed_slot {
+            Some(slot) => slot >= self.last_slot,
+            None => false,
+        }
+    }
+}
+
+/// Result of an attempt to claim a slot range.
+pub enum Claim {
+    Claimed(Lease),
+    //Leased by another worker that is still alive
+    Held,
+    Done,
+}
+
+/// Shared store of the leases of the slot ranges.
+/// Implementations must make claims and renewals atomic across workers.
+pub trait LeaseStore: Send + Sync {
+    /// Claim the range unless it is done or leased by another live worker.
+    /// Expired leases are taken over along with their progress.
+    fn try_claim(&self, first_slot: Slot, last_slot: Slot, owner: &str, ttl: Duration) -> Result<Claim, String>;
+
+    /// Extend the lease and record its progress, if set.
+    /// Returns false if another worker took the range over.
+    fn renew(
+        &self,
+        first_slot: Slot,
+        last_slot: Slot,
+        owner: &str,
+        ttl: Duration,
+        processed_slot: Option<Slot>,
+    ) -> Result<bool, String>;
+}
+
+/// Renews a lease in the background until dropped, so that the lease
+/// does not expire while the rows of the range are slowly written.
+pub struct Heartbeat {
+    is_owned: Arc<AtomicBool>,
+    //Dropped with the heartbeat, which stops the renewals
+    _stop_sender: Sender<()>,
+}
+
+impl Heartbeat {
+    /// Renew the lease every third of its time to live.
+    pub fn start(
+        store: Arc<dyn LeaseStore>,
+        first_slot: Slot,
+        last_slot: Slot,
+        owner: &str,
+        ttl: Duration,
+    ) -> Heartbeat {
+        let (stop_sender, stop_receiver) = mpsc::channel::<()>();
+        let is_owned = Arc::new(AtomicBool::new(true));
+        let renewal_is_owned = Arc::clone(&is_owned);
+        let owner = owner.to_string();
+        let period = ttl / 3;
+        thread::spawn(move || {
+            loop {
+                match stop_receiver.recv_timeout(period) {
+                    Err(RecvTimeoutError::Timeout) => {}
+                    _ => {
+                        return;
+                    }
+                }
+                match store.renew(first_slot, last_slot, &owner, ttl, None) {
+                    Ok(true) => {}
+                    Ok(false) => {
+                        warn!(first_slot, last_slot, "Lease of the range was taken over by another worker.");
+                        renewal_is_owned.store(false, Ordering::SeqCst);
+                        return;
+                    }
+                    Err(err) => {
+                        //Retried on the next beat, the lease is still valid for a while
+                        warn!(first_slot, last_slot, error = %err, "Failed to renew the lease.");
+                    }
+                }
+            }
+        });
+        Heartbeat {
+            is_owned: is_owned,
+            _stop_sender: stop_sender,
+        }
+    }
+
+    /// False once another worker took the range over.
+    pub fn is_owned(&self) -> bool {
+        self.is_owned.load(Ordering::SeqCst)
+    }
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0)
+}
+
+/// Write the value as JSON to a temporary file, then move it over the
+/// file, so that a crash never leaves a partial file.
+pub(crate) fn write_json<T: Serialize>(path: &Path, value: &T) -> Result<(), String> {
+    let contents = serde_json::to_string(value)
+        .map_err(|err| format!("Failed to serialize {}: {}", path.display(), err))?;
+    let staging_path = path.with_extension("json.tmp");
+    fs::write(&staging_path, contents)
+        .map_err(|err| format!("Failed to write {}: {}", staging_path.display(), err))?;
+    fs::rename(&staging_path, path)
+        .map_err(|err| format!("Failed to rename {}: {}", staging_path.display(), err))?;
+    return Ok(());
+}
+
+//Token written in a lock file, tells apart the holders of the lock
+fn lock_token() -> String {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_nanos())
+        .unwrap_or(0);
+    format!("{}-{}-{}", process::id(), nanos, LOCK_COUNT.fetch_add(1, Ordering::SeqCst))
+}
+
+//Token of the lock file if it was left by a crashed worker
+fn stale_token(path: &Path) -> Option<String> {
+    let age = fs::metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .ok()?
+        .elapsed()
+        .ok()?;
+    if age <= STALE_LOCK {
+        return None;
+    }
+    return fs::read_to_string(path).ok();
+}
+
+// Remove the lock file if it still holds the token. The file is first moved
+// to a name of the caller, so that only one of the workers racing for it
+// gets it, and it is put back if it turns out to be a lock taken since.
+fn remove_lock(path: &Path, token: &str, aside_path: &Path) -> bool {
+    if fs::rename(path, aside_path).is_err() {
+        return false;
+    }
+    let is_removed = fs::read_to_string(aside_path)
+        .map(|contents| contents == token)
+        .unwrap_or(false);
+    if !is_removed {
+        //A link does not replace a lock taken in the meantime
+        let _ = fs::hard_link(aside_path, path);
+    }
+    let _ = fs::remove_file(aside_path);
+    return is_removed;
+}
+
+// Lock file held while a lease is read and written, removed on drop.
+// The file holds the token of its holder.
+struct FileLock {
+    path: PathBuf,
+    token: String,
+}
+
+impl FileLock {
+    fn acquire(path: PathBuf) -> Result<FileLock, String> {
+        let token = lock_token();
+        //Linked into place once written, so a lock file always has its token
+        let staging_path = path.with_extension(format!("lock.{}", token));
+        fs::write(&staging_path, &token)
+            .map_err(|err| format!("Failed to write {}: {}", staging_path.display(), err))?;
+        loop {
+            match fs::hard_link(&staging_path, &path) {
+                Ok(()) => {
+                    let _ = fs::remove_file(&staging_path);
+                    return Ok(FileLock {
+                        path: path,
+                        token: token,
+                    });
+                }
+                Err(err) if err.kind() == ErrorKind::AlreadyExists => {
+                    match stale_token(&path) {
+                        Some(stale_token) => {
+                            let aside_path = path.with_extension(format!("stale.{}", token));
+                            remove_lock(&path, &stale_token, &aside_path);
+                        }
+                        None => {
+                            thread::sleep(LOCK_WAIT);
+                        }
+                    }
+                }
+                Err(err) => {
+                    let _ = fs::remove_file(&staging_path);
+                    return Err(format!("Failed to lock {}: {}", path.display(), err));
+                }
+            }
+        }
+    }
+}
+
+impl Drop for FileLock {
+    fn drop(&mut self) {
+        //The lock may have been taken over if it was held for too long
+        let aside_path = self.path.with_extension(format!("released.{}", self.token));
+        remove_lock(&self.path, &self.token, &aside_path);
+    }
+}
+
+/// Lease store backed by a directory, one JSON file per slot range.
+/// Stands in for a shared key value store. The directory can be shared
+/// by workers on several hosts, e.g. through NFS.
+pub struct FileLeaseStore {
+    dir: PathBuf,
+}
+
+impl FileLeaseStore {
+    pub fn new(dir: &str) -> Result<FileLeaseStore, String> {
+        fs::create_dir_all(dir)
+            .map_err(|err| format!("Failed to create {}: {}", dir, err))?;
+        return Ok(FileLeaseStore {
+            dir: Path::new(dir).to_path_buf(),
+        });
+    }
+
+    fn lease_path(&self, first_slot: Slot, last_slot: Slot) -> PathBuf {
+        self.dir.join(format!("range-{}-{}.json", first_slot, last_slot))
+    }
+
+    fn lock(&self, first_slot: Slot, last_slot: Slot) -> Result<FileLock, String> {
+        FileLock::acquire(self.dir.join(format!("range-{}-{}.lock", first_slot, last_slot)))
+    }
+
+    fn read(&self, path: &Path) -> Result<Option<Lease>, String> {
+        if !path.exists() {
+            return Ok(None);
+        }
+        let contents = fs::read_to_string(path)
+            .map_err(|err| format!("Failed to read {}: {}", path.display(), err))?;
+        let lease = serde_json::from_str(&contents)
+            .map_err(|err| format!("Failed to parse {}: {}", path.display(), err))?;
+        return Ok(Some(lease));
+    }
+
+}
+
+impl LeaseStore for FileLeaseStore {
+    fn try_claim(&self, first_slot: Slot, last_slot: Slot, owner: &str, ttl: Duration) -> Result<Claim, String> {
+        let _lock = self.lock(first_slot, last_slot)?;
+        let path = self.lease_path(first_slot, last_slot);
+        let mut processed_slot: Option<Slot> = None;
+        if let Some(lease) = self.read(&path)? {
+            if lease.is_done() {
+                return Ok(Claim::Done);
+            }
+            if lease.owner != owner && lease.expires_at > now_secs() {
+                return Ok(Claim::Held);
+            }
+            processed_slot = lease.processed_slot;
+        }
+        let lease = Lease {
+            first_slot: first_slot,
+            last_slot: last_slot,
+            owner: owner.to_string(),
+            expires_at: now_secs() + ttl.as_secs(),
+            processed_slot: processed_slot,
+        };
+        write_json(&path, &lease)?;
+        return Ok(Claim::Claimed(lease));
+    }
+
+    fn renew(
+        &self,
+        first_slot: Slot,
+        last_slot: Slot,
+        owner: &str,
+        ttl: Duration,
+        processed_slot: Option<Slot>,
+    ) -> Result<bool, String> {
+        let _lock = self.lock(first_slot, last_slot)?;
+        let path = self.lease_path(first_slot, last_slot);
+        let mut lease: Lease;
+        match self.read(&path)? {
+            Some(l) => {
+                lease = l;
+            }
+            None => {
+                return Ok(false);
+            }
+        }
+        if lease.owner != owner {
+            return Ok(false);
+        }
+        lease.expires_at = now_secs() + ttl.as_secs();
+        if processed_slot.is_some() {
+            lease.processed_slot = processed_slot;
+        }
+        write_json(&path, &lease)?;
+        return Ok(true);
+    }
+}
+
+// Claim the first range that is not done nor held by a live worker and
+// ingest it, until every range up to the end slot is done
+fn work(config: &Config, store: Arc<dyn LeaseStore>, owner: &str, start_slot: Slot) -> Result<(), String> {
+    let mut solana_client = SolanaRpc::new(&config.rpc_endpoints);
+    let range_slots = config.lease.range_slots;
+    let ttl = config.lease.ttl();
+    let end_slot = config.end_slot.unwrap_or(Slot::MAX);
+    //Ranges before this one are done
+    let mut first_open_range: u64 = 0;
+    loop {
+        //Ranges are only claimed once their first slot can be processed
+        let available_slot = solana_client.get_latest_slot()
+            .saturating_sub(config.slots_behind_latest);
+        let mut claimed: Option<Lease> = None;
+        let mut is_all_done = true;
+        let mut range = first_open_range;
+        loop {
+            let first_slot = start_slot + range * range_slots;
+            if first_slot > end_slot {
+                break;
+            }
+            if first_slot > available_slot {
+                is_all_done = false;
+                break;
+            }
+            let last_slot = (first_slot + range_slots - 1).min(end_slot);
+            match store.try_claim(first_slot, last_slot, owner, ttl)? {
+                Claim::Claimed(lease) => {
+                    claimed = Some(lease);
+                    break;
+                }
+                Claim::Held => {
+                    is_all_done = false;
+                }
+                Claim::Done => {
+                    if is_all_done {
+                        first_open_range = range + 1;
+                    }
+                }
+            }
+            range += 1;
+        }
+
+        match claimed {
+            Some(lease) => {
+                info!(
+                    first_slot = lease.first_slot,
+                    last_slot = lease.last_slot,
+                    processed_slot = ?lease.processed_slot,
+                    "Claimed slot range."
+                );
+                let progress = ShardProgress::from_lease(lease, Arc::clone(&store), owner, ttl);
+                let mut listener = Listener::for_shard(config.clone(), progress)?;
+                listener.listen()?;
+            }
+            None => {
+                if is_all_done {
+                    info!("Every slot range is done.");
+                    return Ok(());
+                }
+                //Ranges held by dead workers are claimed once their lease expires
+                thread::sleep(IDLE_WAIT);
+            }
+        }
+    }
+}
+
+/// Ingest the slots from the start slot with workers that claim ranges
+/// of slots from the lease store. Instances sharing the store split the
+/// slots between them and take over the ranges of dead instances.
+/// Every instance must use the same start slot and range size.
+pub fn run(config: &Config) -> Result<(), String> {
+    let dir = config.lease.dir
+        .as_ref()
+        .ok_or_else(|| "Lease directory is required".to_string())?;
+    let start_slot = config.start_slot
+        .ok_or_else(|| "Start slot is required when workers claim leases".to_string())?;
+    let store: Arc<dyn LeaseStore> = Arc::new(FileLeaseStore::new(dir)?);
+    let owner = config.lease.owner();
+    //The processors and getBlock requests are shared out between the workers
+    let mut worker_config = config.clone();
+    worker_config.max_processor_count = (config.max_processor_count / config.lease.workers).max(1);
+    worker_config.prefetch_window = (config.prefetch_window / config.lease.workers).max(1);
+
+    let mut handles: Vec<thread::JoinHandle<Result<(), String>>> = Vec::new();
+    for worker in 0..config.lease.workers {
+        let store = Arc::clone(&store);
+        let worker_config = worker_config.clone();
+        //Workers of the same instance hold their own leases
+        let worker_owner = format!("{}-{}", owner, worker);
+        handles.push(thread::spawn(move || {
+            let span = info_span!("worker", owner = worker_owner.as_str());
+            let _enter = span.enter();
+            work(&worker_config, store, &worker_owner, start_slot)
+        }));
+    }
+    for handle in handles {
+        handle.join().map_err(|_| "Worker panicked".to_string())??;
+    }
+    return Ok(());
+}
@@ -1,6 +1,39 @@
+mod amount;
+mod balance_change;
+mod batch_writer;
 mod bigquery;
 mod block;
-mod counter;
+mod compute_budget;
+mod concurrency;
+mod continuity;
+mod filter;
+mod gcp_auth;
+mod leader_schedule;
+mod load_job;
+mod log_message;
+mod metrics;
+mod prefetch;
+mod proto_row;
+mod slot_status;
 mod solana_rpc;
+mod storage_write;
 mod transaction;
+mod transfer;
+mod vote;
+pub mod backfill;
 pub mod block_listener;
+pub mod config;
+pub mod epoch;
+pub mod health;
+pub mod lease;
+pub mod logging;
+pub mod schema;
+pub mod server;
+pub use bigquery::{
+    recover_staged_chunks,
+    SinkBackend,
+};
+pub use continuity::ContinuityMode;
+pub use filter::TransactionFilter;
+pub use storage_write::StreamType;
+pub use vote::VoteMode;
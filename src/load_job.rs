@@ -0,0 +1,427 @@
+use std::{
+    fs::{
+        self,
+        File,
+    },
+    io::{
+        BufWriter,
+        Write,
+    },
+    path::{
+        Path,
+        PathBuf,
+    },
+    process,
+    sync::atomic::{
+        AtomicU64,
+        Ordering,
+    },
+    time::{
+        Duration,
+        Instant,
+        SystemTime,
+        UNIX_EPOCH,
+    },
+};
+
+use reqwest::StatusCode;
+use serde::Serialize;
+use serde_json::{
+    json,
+    Value,
+};
+use solana_sdk::clock::Slot;
+use tracing::info;
+
+use crate::{
+    config::Config,
+    gcp_auth::{
+        self,
+        GcpAuthenticator,
+    },
+    health::HEALTH,
+    schema::{
+        BALANCE_CHANGES_TABLE_ID,
+        BLOCKS_TABLE_ID,
+        SLOTS_TABLE_ID,
+        TRANSACTIONS_TABLE_ID,
+        TRANSFERS_TABLE_ID,
+        VOTES_TABLE_ID,
+    },
+};
+
+const BIGQUERY_URL: &str = "https://bigquery.googleapis.com/bigquery/v2";
+const BIGQUERY_UPLOAD_URL: &str = "https://bigquery.googleapis.com/upload/bigquery/v2";
+const STORAGE_UPLOAD_URL: &str = "https://storage.googleapis.com/upload/storage/v1";
+const BOUNDARY: &str = "solistener_load_job";
+const FILE_EXTENSION: &str = "json";
+const STAGING_SUFFIX: &str = ".staging";
+//Blocks are loaded last, the latest block is where the listener resumes
+const LOAD_ORDER: [&str; 6] = [
+    TRANSACTIONS_TABLE_ID,
+    VOTES_TABLE_ID,
+    TRANSFERS_TABLE_ID,
+    BALANCE_CHANGES_TABLE_ID,
+    SLOTS_TABLE_ID,
+    BLOCKS_TABLE_ID,
+];
+
+//Chunks staged by this process, part of their nonce
+static CHUNK_SEQUENCE: AtomicU64 = AtomicU64::new(0);
+
+// Unique per chunk, across processes and restarts
+fn chunk_nonce() -> String {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_nanos())
+        .unwrap_or(0);
+    let sequence = CHUNK_SEQUENCE.fetch_add(1, Ordering::Relaxed);
+    format!("{:x}{:x}{:x}", nanos, process::id(), sequence)
+}
+
+/// Rows of a slot range written to one newline delimited JSON file per
+/// table. The chunk is staged under a temporary name and renamed once
+/// every file is written, so a crash never leaves a partial chunk.
+/// Its name ends with a nonce, so that chunks of the same slot range, e.g.
+/// of a backfill run again, are never mixed up, nor their load jobs.
+pub struct StagedChunk {
+    staging_path: PathBuf,
+    path: PathBuf,
+}
+
+impl StagedChunk {
+    pub fn create(staging_dir: &str, first_slot: Slot, last_slot: Slot) -> Result<StagedChunk, String> {
+        //Zero padded so that chunks sort by slot
+        let name = format!("{:012}-{:012}-{}", first_slot, last_slot, chunk_nonce());
+        let path = Path::new(staging_dir).join(&name);
+        let staging_path = Path::new(staging_dir).join(format!("{}{}", name, STAGING_SUFFIX));
+        //Files left by a crash must not be loaded with the new rows
+        if staging_path.exists() {
+            fs::remove_dir_all(&staging_path)
+                .map_err(|err| format!("Failed to remove {}: {}", staging_path.display(), err))?;
+        }
+        fs::create_dir_all(&staging_path)
+            .map_err(|err| format!("Failed to create {}: {}", staging_path.display(), err))?;
+        return Ok(StagedChunk {
+            staging_path: staging_path,
+            path: path,
+        });
+    }
+
+    pub fn write<T: Serialize>(&self, table_id: &str, rows: &[T]) -> Result<(), String> {
+        if rows.is_empty() {
+            return Ok(());
+        }
+        let file_path = self.staging_path.join(format!("{}.{}", table_id, FILE_EXTENSION));
+        let file = File::create(&file_path)
+            .map_err(|err| format!("Failed to create {}: {}", file_path.display(), err))?;
+        let mut writer = BufWriter::new(file);
+        for row in rows {
+            serde_json::to_writer(&mut writer, row)
+                .map_err(|err| format!("Failed to serialize row: {}", err))?;
+            writer.write_all(b"\n")
+                .map_err(|err| format!("Failed to write {}: {}", file_path.display(), err))?;
+        }
+        writer.flush()
+            .map_err(|err| format!("Failed to write {}: {}", file_path.display(), err))?;
+        return Ok(());
+    }
+
+    pub fn finish(self) -> Result<PathBuf, String> {
+        fs::rename(&self.staging_path, &self.path)
+            .map_err(|err| format!("Failed to rename {}: {}", self.staging_path.display(), err))?;
+        return Ok(self.path);
+    }
+}
+
+/// Chunks that were staged but not fully loaded, e.g. by a previous run,
+/// in slot order.
+pub fn staged_chunks(staging_dir: &str) -> Vec<PathBuf> {
+    let mut chunks: Vec<PathBuf> = Vec::new();
+    if let Ok(entries) = fs::read_dir(staging_dir) {
+        for entry in entries.flatten() {
+            let path = entry.path();
+            let is_staging = path
+                .to_string_lossy()
+                .ends_with(STAGING_SUFFIX);
+            if path.is_dir() && !is_staging {
+                chunks.push(path);
+            }
+        }
+    }
+    chunks.sort();
+    return chunks;
+}
+
+/// Remove the chunks whose staging was interrupted by a crash. Their rows
+/// are processed again, since the progress is saved once they are loaded.
+pub fn remove_partial_chunks(staging_dir: &str) -> Result<(), String> {
+    if let Ok(entries) = fs::read_dir(staging_dir) {
+        for entry in entries.flatten() {
+            let path = entry.path();
+            if path.is_dir() && path.to_string_lossy().ends_with(STAGING_SUFFIX) {
+                fs::remove_dir_all(&path)
+                    .map_err(|err| format!("Failed to remove {}: {}", path.display(), err))?;
+            }
+        }
+    }
+    return Ok(());
+}
+
+/// Client that loads staged chunks with BigQuery load jobs, either
+/// uploaded directly or through a Cloud Storage bucket.
+pub struct LoadJobs {
+    http: reqwest::Client,
+    authenticator: GcpAuthenticator,
+    project_id: String,
+    dataset_id: String,
+    location: Option<String>,
+    bucket: Option<String>,
+    poll_interval: Duration,
+    job_timeout: Duration,
+}
+
+// State of a submitted load job
+enum JobState {
+    Missing,
+    Running,
+    //With the error of the job if it failed
+    Done(Option<String>),
+}
+
+impl LoadJobs {
+    pub async fn new(gcp_key: &str, config: &Config) -> Result<LoadJobs, String> {
+        return Ok(LoadJobs {
+            http: reqwest::Client::new(),
+            authenticator: gcp_auth::authenticator(gcp_key).await?,
+            project_id: config.project_id.clone(),
+            dataset_id: config.dataset_id.clone(),
+            location: config.tables.location.clone(),
+            bucket: config.load_job.bucket.clone(),
+            poll_interval: config.load_job.poll_interval(),
+            job_timeout: config.load_job.job_timeout(),
+        });
+    }
+
+    // Job IDs are derived from the chunk, nonce included, so that a job
+    // submitted before a crash is found again instead of loading the rows
+    // twice, while a new chunk of the same slots gets new jobs
+    fn job_id(&self, chunk: &Path, table_id: &str, attempt: u32) -> String {
+        let chunk_name = chunk
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_default();
+        format!("solistener_{}_{}_{}_{}", self.dataset_id, table_id, chunk_name, attempt)
+    }
+
+    fn job(&self, job_id: &str, table_id: &str, source_uri: Option<String>) -> Value {
+        let mut load = json!({
+            "destinationTable": {
+                "projectId": self.project_id,
+                "datasetId": self.dataset_id,
+                "tableId": table_id,
+            },
+            "sourceFormat": "NEWLINE_DELIMITED_JSON",
+            "writeDisposition": "WRITE_APPEND",
+        });
+        if let Some(uri) = source_uri {
+            load["sourceUris"] = json!([uri]);
+        }
+        let mut job_reference = json!({
+            "projectId": self.project_id,
+            "jobId": job_id,
+        });
+        if let Some(location) = &self.location {
+            job_reference["location"] = json!(location);
+        }
+        return json!({
+            "jobReference": job_reference,
+            "configuration": {
+                "load": load,
+            },
+        });
+    }
+
+    async fn upload_to_bucket(&self, bucket: &str, object: &str, data: Vec<u8>) -> Result<String, String> {
+        let token = gcp_auth::access_token(&self.authenticator).await?;
+        let url = format!(
+            "{}/b/{}/o?uploadType=media&name={}",
+            STORAGE_UPLOAD_URL,
+            bucket,
+            object.replace('/', "%2F"),
+        );
+        let response = self.http
+            .post(&url)
+            .bearer_auth(token)
+            .header("Content-Type", "application/octet-stream")
+            .body(data)
+            .send()
+            .await
+            .map_err(|err| format!("Failed to upload {}: {}", object, err))?;
+        if !response.status().is_success() {
+            return Err(format!("Failed to upload {}: {}", object, response.status()));
+        }
+        return Ok(format!("gs://{}/{}", bucket, object));
+    }
+
+    // Submit the job, uploading the file with it unless a bucket is used.
+    // Returns false if the job already exists.
+    async fn insert_job(&self, job_id: &str, table_id: &str, file: &Path) -> Result<bool, String> {
+        let data = fs::read(file)
+            .map_err(|err| format!("Failed to read {}: {}", file.display(), err))?;
+        let token = gcp_auth::access_token(&self.authenticator).await?;
+        let request: reqwest::RequestBuilder;
+        match &self.bucket {
+            Some(bucket) => {
+                let object = format!("{}/{}.{}", self.dataset_id, job_id, FILE_EXTENSION);
+                let uri = self.upload_to_bucket(bucket, &object, data).await?;
+                request = self.http
+                    .post(&format!("{}/projects/{}/jobs", BIGQUERY_URL, self.project_id))
+                    .bearer_auth(token)
+                    .json(&self.job(job_id, table_id, Some(uri)));
+            }
+            None => {
+                //multipart/related body with the job followed by the rows
+                let mut body: Vec<u8> = Vec::new();
+                body.extend_from_slice(format!(
+                    "--{}\r\nContent-Type: application/json; charset=UTF-8\r\n\r\n{}\r\n--{}\r\nContent-Type: application/octet-stream\r\n\r\n",
+                    BOUNDARY,
+                    self.job(job_id, table_id, None),
+                    BOUNDARY,
+                ).as_bytes());
+                body.extend_from_slice(&data);
+                body.extend_from_slice(format!("\r\n--{}--\r\n", BOUNDARY).as_bytes());
+                request = self.http
+                    .post(&format!(
+                        "{}/projects/{}/jobs?uploadType=multipart",
+                        BIGQUERY_UPLOAD_URL,
+                        self.project_id,
+                    ))
+                    .bearer_auth(token)
+                    .header("Content-Type", format!("multipart/related; boundary={}", BOUNDARY))
+                    .body(body);
+            }
+        }
+        let response = request
+            .send()
+            .await
+            .map_err(|err| format!("Failed to submit job {}: {}", job_id, err))?;
+        match response.status() {
+            StatusCode::CONFLICT => {
+                return Ok(false);
+            }
+            status if status.is_success() => {
+                return Ok(true);
+            }
+            status => {
+                let text = response.text().await.unwrap_or_default();
+                return Err(format!("Failed to submit job {}: {} {}", job_id, status, text));
+            }
+        }
+    }
+
+    async fn job_state(&self, job_id: &str) -> Result<JobState, String> {
+        let mut url = format!("{}/projects/{}/jobs/{}", BIGQUERY_URL, self.project_id, job_id);
+        if let Some(location) = &self.location {
+            url = format!("{}?location={}", url, location);
+        }
+        let token = gcp_auth::access_token(&self.authenticator).await?;
+        let response = self.http
+            .get(&url)
+            .bearer_auth(token)
+            .send()
+            .await
+            .map_err(|err| format!("Failed to get job {}: {}", job_id, err))?;
+        if response.status() == StatusCode::NOT_FOUND {
+            return Ok(JobState::Missing);
+        }
+        let job: Value = response
+            .json()
+            .await
+            .map_err(|err| format!("Failed to parse job {}: {}", job_id, err))?;
+        let status = &job["status"];
+        if status["state"] != "DONE" {
+            return Ok(JobState::Running);
+        }
+        if status["errorResult"].is_null() {
+            return Ok(JobState::Done(None));
+        }
+        return Ok(JobState::Done(Some(status["errorResult"].to_string())));
+    }
+
+    // Wait for the job to be done, up to the job timeout.
+    // Returns its error if it failed.
+    async fn wait_for_job(&self, job_id: &str) -> Result<Option<String>, String> {
+        let start = Instant::now();
+        loop {
+            match self.job_state(job_id).await? {
+                JobState::Done(error) => {
+                    return Ok(error);
+                }
+                JobState::Missing => {
+                    return Err(format!("Job {} was not found", job_id));
+                }
+                JobState::Running => {
+                    //Loads of staged chunks at startup take longer than the
+                    //staleness window, a running job counts as progress
+                    HEALTH.record_progress();
+                }
+            }
+            if start.elapsed() >= self.job_timeout {
+                return Err(format!(
+                    "Job {} is not done after {} seconds",
+                    job_id,
+                    self.job_timeout.as_secs(),
+                ));
+            }
+            tokio::time::sleep(self.poll_interval).await;
+        }
+    }
+
+    // A job of the previous attempt that timed out may still load the file
+    async fn is_loaded_by_previous_attempt(&self, chunk: &Path, table_id: &str, attempt: u32) -> Result<bool, String> {
+        if attempt == 0 {
+            return Ok(false);
+        }
+        let job_id = self.job_id(chunk, table_id, attempt - 1);
+        match self.job_state(&job_id).await? {
+            JobState::Missing | JobState::Done(Some(_)) => {
+                return Ok(false);
+            }
+            JobState::Done(None) => {
+                return Ok(true);
+            }
+            JobState::Running => {
+                info!(job_id = job_id.as_str(), "Wait for the load job of the previous attempt.");
+                return Ok(self.wait_for_job(&job_id).await?.is_none());
+            }
+        }
+    }
+
+    /// Load the files of a chunk that are left, in order, deleting each one
+    /// once its job succeeded and the chunk once it is empty.
+    /// The attempt is part of the job IDs since failed jobs cannot be resubmitted.
+    pub async fn load(&self, chunk: &Path, attempt: u32) -> Result<(), String> {
+        for table_id in LOAD_ORDER.iter() {
+            let file = chunk.join(format!("{}.{}", table_id, FILE_EXTENSION));
+            if !file.exists() {
+                continue;
+            }
+            if !self.is_loaded_by_previous_attempt(chunk, table_id, attempt).await? {
+                let job_id = self.job_id(chunk, table_id, attempt);
+                if !self.insert_job(&job_id, table_id, &file).await? {
+                    info!(job_id = job_id.as_str(), "Load job already submitted.");
+                }
+                if let Some(error) = self.wait_for_job(&job_id).await? {
+                    return Err(format!("Load job {} failed: {}", job_id, error));
+                }
+                info!(job_id = job_id.as_str(), table = *table_id, "Load job done.");
+            }
+            fs::remove_file(&file)
+                .map_err(|err| format!("Failed to remove {}: {}", file.display(), err))?;
+        }
+        fs::remove_dir(chunk)
+            .map_err(|err| format!("Failed to remove {}: {}", chunk.display(), err))?;
+        return Ok(());
+    }
+}
@@ -0,0 +1,266 @@
+use serde::Serialize;
+
+use crate::{
+    bigquery_record,
+    schema::Base64,
+};
+
+const INVOKE_PREFIX: &str = " invoke [";
+const SUCCESS_SUFFIX: &str = " success";
+const FAILED_INFIX: &str = " failed: ";
+const CONSUMED_INFIX: &str = " consumed ";
+const LOG_PREFIX: &str = "Program log: ";
+const DATA_PREFIX: &str = "Program data: ";
+const PROGRAM_PREFIX: &str = "Program ";
+
+bigquery_record! {
+    /// A single program invocation reconstructed from the log messages
+    /// of a transaction. Inner invocations (CPIs) have a depth above 1.
+    #[derive(Serialize)]
+    pub struct Invocation {
+        pub program_id: String,
+        pub depth: u64,
+        pub is_successful: Option<bool>,
+        pub error: Option<String>,
+        pub compute_units_consumed: Option<u64>,
+        pub compute_units_limit: Option<u64>,
+        pub logs: Vec<String>,
+        //Base64 encoded fields of "Program data:" events (e.g. Anchor events)
+        pub events: Vec<Base64>,
+    }
+}
+
+
+impl Invocation {
+    fn new(program_id: &str, depth: u64) -> Invocation {
+        Invocation {
+            program_id: program_id.to_string(),
+            depth: depth,
+            is_successful: None,
+            error: None,
+            compute_units_consumed: None,
+            compute_units_limit: None,
+            logs: Vec::new(),
+            events: Vec::new(),
+        }
+    }
+}
+
+// "Program <id> invoke [<depth>]"
+fn parse_invoke(message: &str) -> Option<(&str, u64)> {
+    let rest = message.strip_prefix(PROGRAM_PREFIX)?;
+    let invoke_start = rest.find(INVOKE_PREFIX)?;
+    let program_id = &rest[..invoke_start];
+    let depth = rest[invoke_start + INVOKE_PREFIX.len()..]
+        .strip_suffix(']')?
+        .parse()
+        .ok()?;
+    return Some((program_id, depth));
+}
+
+// "Program <id> consumed <consumed> of <limit> compute units"
+fn parse_consumed(message: &str) -> Option<(&str, u64, u64)> {
+    let rest = message.strip_prefix(PROGRAM_PREFIX)?;
+    let consumed_start = rest.find(CONSUMED_INFIX)?;
+    let program_id = &rest[..consumed_start];
+    let units: Vec<&str> = rest[consumed_start + CONSUMED_INFIX.len()..]
+        .strip_suffix(" compute units")?
+        .split(" of ")
+        .collect();
+    if units.len() != 2 {
+        return None;
+    }
+    let consumed = units[0].parse().ok()?;
+    let limit = units[1].parse().ok()?;
+    return Some((program_id, consumed, limit));
+}
+
+// "Program data: <base64> <base64> ..."
+// Each field is a separate event, fields that are not base64 are dropped.
+fn parse_data(data: &str) -> Vec<Base64> {
+    let mut events: Vec<Base64> = Vec::new();
+    for field in data.split_whitespace() {
+        if base64::decode(field).is_ok() {
+            events.push(Base64(field.to_string()));
+        }
+    }
+    return events;
+}
+
+/// Compute units consumed by a transaction, added up from what each top
+/// level invocation logged. None if none of them logged it.
+pub fn compute_units_consumed<'a>(invocations: impl Iterator<Item = &'a Invocation>) -> Option<u64> {
+    let mut compute_units_consumed: Option<u64> = None;
+    for invocation in invocations {
+        if invocation.depth != 1 {
+            continue;
+        }
+        if let Some(consumed) = invocation.compute_units_consumed {
+            compute_units_consumed = Some(compute_units_consumed.unwrap_or(0) + consumed);
+        }
+    }
+    return compute_units_consumed;
+}
+
+/// Group the log messages of a transaction by top level instruction.
+/// Each group lists the invocations made by that instruction in the
+/// order in which they were invoked.
+pub fn parse_invocations(messages: &[String]) -> Vec<Vec<Invocation>> {
+    let mut instructions: Vec<Vec<Invocation>> = Vec::new();
+    //Indexes into the invocations of the current instruction
+    let mut stack: Vec<usize> = Vec::new();
+
+    for message in messages {
+        if let Some((program_id, depth)) = parse_invoke(message) {
+            if depth <= 1 || instructions.is_empty() {
+                instructions.push(Vec::new());
+                stack.clear();
+            }
+            let invocations = instructions.last_mut().unwrap();
+            invocations.push(Invocation::new(program_id, depth));
+            stack.push(invocations.len() - 1);
+            continue;
+        }
+
+        let invocation = match (instructions.last_mut(), stack.last()) {
+            (Some(invocations), Some(index)) => &mut invocations[*index],
+            _ => continue,
+        };
+
+        if let Some(log) = message.strip_prefix(LOG_PREFIX) {
+            invocation.logs.push(log.to_string());
+        } else if let Some(data) = message.strip_prefix(DATA_PREFIX) {
+            invocation.events.extend(parse_data(data));
+        } else if let Some((_, consumed, limit)) = parse_consumed(message) {
+            invocation.compute_units_consumed = Some(consumed);
+            invocation.compute_units_limit = Some(limit);
+        } else if message.starts_with(PROGRAM_PREFIX)
+            && message.ends_with(SUCCESS_SUFFIX) {
+            invocation.is_successful = Some(true);
+            stack.pop();
+        } else if let Some(failed_start) = message.find(FAILED_INFIX) {
+            if message.starts_with(PROGRAM_PREFIX) {
+                invocation.is_successful = Some(false);
+                invocation.error = Some(
+                    message[failed_start + FAILED_INFIX.len()..].to_string());
+                stack.pop();
+            } else {
+                invocation.logs.push(message.clone());
+            }
+        } else {
+            invocation.logs.push(message.clone());
+        }
+    }
+    return instructions;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn logs(messages: &[&str]) -> Vec<String> {
+        return messages.iter().map(|message| message.to_string()).collect();
+    }
+
+    #[test]
+    fn nested_invocations() {
+        let instructions = parse_invocations(&logs(&[
+            "Program Token1 invoke [1]",
+            "Program log: Instruction: Transfer",
+            "Program Inner1 invoke [2]",
+            "Program log: inner",
+            "Program Inner1 consumed 500 of 199000 compute units",
+            "Program Inner1 success",
+            "Program log: after inner",
+            "Program Token1 consumed 2000 of 200000 compute units",
+            "Program Token1 success",
+            "Program Memo1 invoke [1]",
+            "Program Memo1 success",
+        ]));
+        assert_eq!(instructions.len(), 2);
+
+        let first = &instructions[0];
+        assert_eq!(first.len(), 2);
+        assert_eq!(first[0].program_id, "Token1");
+        assert_eq!(first[0].depth, 1);
+        assert_eq!(first[0].logs, vec!["Instruction: Transfer", "after inner"]);
+        assert_eq!(first[0].compute_units_consumed, Some(2000));
+        assert_eq!(first[0].compute_units_limit, Some(200000));
+        assert_eq!(first[0].is_successful, Some(true));
+        assert_eq!(first[1].program_id, "Inner1");
+        assert_eq!(first[1].depth, 2);
+        assert_eq!(first[1].logs, vec!["inner"]);
+        assert_eq!(first[1].compute_units_consumed, Some(500));
+
+        let second = &instructions[1];
+        assert_eq!(second.len(), 1);
+        assert_eq!(second[0].program_id, "Memo1");
+        assert_eq!(second[0].compute_units_consumed, None);
+
+        let consumed = compute_units_consumed(instructions.iter().flatten());
+        assert_eq!(consumed, Some(2000));
+    }
+
+    #[test]
+    fn failed_invocation() {
+        let instructions = parse_invocations(&logs(&[
+            "Program Token1 invoke [1]",
+            "Program Inner1 invoke [2]",
+            "Program Inner1 failed: custom program error: 0x1",
+            "Program Token1 consumed 3000 of 200000 compute units",
+            "Program Token1 failed: custom program error: 0x1",
+        ]));
+        assert_eq!(instructions.len(), 1);
+        let invocations = &instructions[0];
+        assert_eq!(invocations[0].is_successful, Some(false));
+        assert_eq!(invocations[0].error.as_deref(), Some("custom program error: 0x1"));
+        assert_eq!(invocations[0].compute_units_consumed, Some(3000));
+        assert_eq!(invocations[1].is_successful, Some(false));
+        assert_eq!(invocations[1].error.as_deref(), Some("custom program error: 0x1"));
+    }
+
+    #[test]
+    fn data_events() {
+        let instructions = parse_invocations(&logs(&[
+            "Program Anchor1 invoke [1]",
+            "Program data: AQID BAU= not-base64!",
+            "Program data: Bg==",
+            "Program Anchor1 success",
+        ]));
+        let events: Vec<&str> = instructions[0][0].events
+            .iter()
+            .map(|event| event.0.as_str())
+            .collect();
+        assert_eq!(events, vec!["AQID", "BAU=", "Bg=="]);
+    }
+
+    #[test]
+    fn truncated_logs() {
+        let instructions = parse_invocations(&logs(&[
+            "Program Token1 invoke [1]",
+            "Program log: Instruction: Transfer",
+            "Log truncated",
+        ]));
+        assert_eq!(instructions.len(), 1);
+        let invocation = &instructions[0][0];
+        assert_eq!(invocation.is_successful, None);
+        assert_eq!(invocation.logs, vec!["Instruction: Transfer", "Log truncated"]);
+        assert_eq!(compute_units_consumed(instructions.iter().flatten()), None);
+    }
+
+    #[test]
+    fn consumed_units_of_top_level_invocations() {
+        let instructions = parse_invocations(&logs(&[
+            "Program A invoke [1]",
+            "Program A consumed 100 of 200000 compute units",
+            "Program A success",
+            "Program B invoke [1]",
+            "Program C invoke [2]",
+            "Program C consumed 40 of 199800 compute units",
+            "Program C success",
+            "Program B consumed 150 of 199900 compute units",
+            "Program B success",
+        ]));
+        assert_eq!(compute_units_consumed(instructions.iter().flatten()), Some(250));
+    }
+}
@@ -0,0 +1,48 @@
+use std::str::FromStr;
+
+use serde::Deserialize;
+use tracing_subscriber::EnvFilter;
+
+const DEFAULT_LOG_LEVEL: &str = "info";
+
+/// Output format of the log events.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    Text,
+    //One JSON object per line, for Cloud Logging or Loki
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(format: &str) -> Result<LogFormat, String> {
+        match format {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(format!("Unknown log format {}", format)),
+        }
+    }
+}
+
+/// Install the global log subscriber.
+/// The level is read from RUST_LOG and defaults to info.
+pub fn init(format: LogFormat) {
+    let filter = EnvFilter::try_from_default_env()
+        .unwrap_or_else(|_| EnvFilter::new(DEFAULT_LOG_LEVEL));
+    let builder = tracing_subscriber::fmt()
+        .with_env_filter(filter);
+    match format {
+        LogFormat::Text => {
+            builder.init();
+        }
+        LogFormat::Json => {
+            builder
+                .json()
+                .with_current_span(true)
+                .with_span_list(true)
+                .init();
+        }
+    }
+}
@@ -1,27 +1,116 @@
 use clap::{
     Arg,
     App,
+    ArgMatches,
 };
 use std::env;
-use solistener::block_listener;
+use solistener::{
+    block_listener,
+    config::Config,
+    health,
+    lease,
+    logging,
+    server,
+};
+
+// Apply the command line arguments on top of the config
+fn apply_args(config: &mut Config, matches: &ArgMatches) {
+    if let Some(project_id) = matches.value_of("project") {
+        config.project_id = project_id.to_string();
+    }
+    if let Some(dataset_id) = matches.value_of("dataset") {
+        config.dataset_id = dataset_id.to_string();
+    }
+    if let Some(endpoints) = matches.values_of("rpc_endpoint") {
+        config.rpc_endpoints = endpoints.map(|e| e.to_string()).collect();
+    }
+    if let Some(slot) = matches.value_of("start_slot") {
+        let s: u64 = slot
+            .parse()
+            .expect("Start slot is not a valid number");
+        config.start_slot = Some(s);
+    }
+    if let Some(slot) = matches.value_of("end_slot") {
+        let s: u64 = slot
+            .parse()
+            .expect("End slot is not a valid number");
+        config.end_slot = Some(s);
+    }
+    if let Some(count) = matches.value_of("max_processor_count") {
+        config.max_processor_count = count
+            .parse()
+            .expect("Max processor count is not a valid number");
+    }
+    if let Some(backend) = matches.value_of("sink_backend") {
+        config.sink.backend = backend
+            .parse()
+            .expect("Sink backend is not valid");
+    }
+    if let Some(votes) = matches.value_of("votes") {
+        config.votes = votes
+            .parse()
+            .expect("Vote mode is not valid");
+    }
+
+    let values = |name: &str| -> Option<Vec<String>> {
+        matches.values_of(name)
+            .map(|values| values.map(|v| v.to_string()).collect())
+    };
+    if let Some(programs) = values("include_program") {
+        config.filter.include_programs = programs;
+    }
+    if let Some(programs) = values("exclude_program") {
+        config.filter.exclude_programs = programs;
+    }
+    if let Some(accounts) = values("include_account") {
+        config.filter.include_accounts = accounts;
+    }
+    if let Some(accounts) = values("exclude_account") {
+        config.filter.exclude_accounts = accounts;
+    }
+
+    if let Some(address) = matches.value_of("http_address") {
+        config.http_address = address.to_string();
+    }
+    if let Some(window) = matches.value_of("staleness_window") {
+        config.staleness_window_secs = window
+            .parse()
+            .expect("Staleness window is not a valid number");
+    }
+    if let Some(format) = matches.value_of("log_format") {
+        config.log_format = format
+            .parse()
+            .expect("Log format is not valid");
+    }
+}
 
 fn main() {
     let matches = App::new("Solistener")
         .version("0.1")
         .author("Diego Wilson <diego.wilson.solis@gmail.com>")
         .about("Listener for Solana transactions.")
+        .arg(Arg::with_name("config")
+            .long("config")
+            .short("c")
+            .env("SOLISTENER_CONFIG")
+            .value_name("FILE")
+            .help("TOML config file. Its settings are overridden by SOLISTENER_* environment variables and by the arguments."))
         .arg(Arg::with_name("project")
             .long("project")
             .short("p")
-            .default_value("datadragonio-stage")
             .value_name("PROJECT")
             .help("Name of the GCP project."))
         .arg(Arg::with_name("dataset")
             .long("dataset")
             .short("d")
-            .default_value("solana_test")
             .value_name("DATASET")
             .help("Name of the dataset that transactions will be written to."))
+        .arg(Arg::with_name("rpc_endpoint")
+            .long("rpc-endpoint")
+            .multiple(true)
+            .number_of_values(1)
+            .value_name("URL")
+            .help("RPC node to fetch blocks from. Can be repeated to fall back on other nodes."))
         .arg(Arg::with_name("start_slot")
             .long("start-slot")
             .short("s")
@@ -32,44 +121,92 @@ fn main() {
             .short("e")
             .value_name("SLOT")
             .help("Stop after processing the block at this slot."))
+        .arg(Arg::with_name("max_processor_count")
+            .long("max-processor-count")
+            .value_name("COUNT")
+            .help("Number of blocks processed concurrently."))
+        .arg(Arg::with_name("sink_backend")
+            .long("sink-backend")
+            .possible_values(&["insert_all", "storage_write", "load_job"])
+            .value_name("BACKEND")
+            .help("Write rows with streaming inserts, with the Storage Write API, or with load jobs of staged files for backfills."))
+        .arg(Arg::with_name("votes")
+            .long("votes")
+            .possible_values(&["include", "skip", "separate"])
+            .value_name("MODE")
+            .help("Record vote transactions with the other transactions, skip them, or record them in the votes table."))
+        .arg(Arg::with_name("include_program")
+            .long("include-program")
+            .multiple(true)
+            .number_of_values(1)
+            .value_name("PROGRAM_ID")
+            .help("Only record transactions that invoke this program. Can be repeated."))
+        .arg(Arg::with_name("exclude_program")
+            .long("exclude-program")
+            .multiple(true)
+            .number_of_values(1)
+            .value_name("PROGRAM_ID")
+            .help("Do not record transactions that invoke this program. Can be repeated."))
+        .arg(Arg::with_name("include_account")
+            .long("include-account")
+            .multiple(true)
+            .number_of_values(1)
+            .value_name("ADDRESS")
+            .help("Only record transactions that reference this account. Can be repeated."))
+        .arg(Arg::with_name("exclude_account")
+            .long("exclude-account")
+            .multiple(true)
+            .number_of_values(1)
+            .value_name("ADDRESS")
+            .help("Do not record transactions that reference this account. Can be repeated."))
+        .arg(Arg::with_name("http_address")
+            .long("http-address")
+            .value_name("ADDRESS")
+            .help("Address of the HTTP server for the /metrics, /healthz and /readyz endpoints."))
+        .arg(Arg::with_name("staleness_window")
+            .long("staleness-window")
+            .value_name("SECONDS")
+            .help("Report unhealthy if the processed slot does not advance for this long."))
+        .arg(Arg::with_name("log_format")
+            .long("log-format")
+            .possible_values(&["text", "json"])
+            .value_name("FORMAT")
+            .help("Format of the log output. The level is set with RUST_LOG."))
         .get_matches();
 
+    let mut config = Config::load(matches.value_of("config"))
+        .expect("Failed to load the config");
+    apply_args(&mut config, &matches);
+    if let Err(err) = config.validate() {
+        panic!("Config is not valid: {}", err);
+    }
+
+    logging::init(config.log_format);
+
     env::var("GOOGLE_APPLICATION_CREDENTIALS")
         .expect("Environment variable GOOGLE_APPLICATION_CREDENTIALS is required");
 
-    let project_id = matches.value_of("project").unwrap();
-    let dataset_id = matches.value_of("dataset").unwrap();
+    health::HEALTH.set_staleness_window(config.staleness_window());
+    server::serve(config.http_address().unwrap());
 
-    let start_slot: Option<u64>;
-    match matches.value_of("start_slot") {
-        None => {
-            start_slot = None;
-        }
-        Some(slot) => {
-            let s: u64 = slot
-                .parse()
-                .expect("Start slot is not a valid number");
-            start_slot = Some(s);
-        }
+    //Chunks left by a previous run are loaded before anything else
+    if let Err(err) = solistener::recover_staged_chunks(&config) {
+        panic!("Failed to recover the staged chunks: {}", err);
     }
 
-    let end_slot: Option<u64>;
-    match matches.value_of("end_slot") {
-        None => {
-            end_slot = None;
-        }
-        Some(slot) => {
-            let s: u64 = slot
-                .parse()
-                .expect("End slot is not a valid number");
-            end_slot = Some(s);
+    //Instances sharing a lease store split the slots between them
+    if config.lease.dir.is_some() {
+        if let Err(err) = lease::run(&config) {
+            panic!("Workers failed: {}", err);
         }
+        return;
+    }
+
+    let mut processor = match block_listener::Listener::new(config) {
+        Ok(processor) => processor,
+        Err(err) => panic!("Failed to start the listener: {}", err),
+    };
+    if let Err(err) = processor.listen() {
+        panic!("Listener failed: {}", err);
     }
-    let mut processor = block_listener::Listener::new(
-        project_id,
-        dataset_id,
-        start_slot,
-        end_slot,
-    );
-    processor.listen();
 }
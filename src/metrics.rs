@@ -0,0 +1,92 @@
+use lazy_static::lazy_static;
+use prometheus::{
+    register_histogram_vec,
+    register_int_counter,
+    register_int_counter_vec,
+    register_int_gauge,
+    Encoder,
+    HistogramVec,
+    IntCounter,
+    IntCounterVec,
+    IntGauge,
+    TextEncoder,
+};
+use tracing::error;
+
+lazy_static! {
+    pub static ref PROCESSED_SLOT: IntGauge = register_int_gauge!(
+        "solistener_processed_slot",
+        "Latest slot handed to a block processor."
+    ).unwrap();
+    pub static ref LATEST_SLOT: IntGauge = register_int_gauge!(
+        "solistener_latest_slot",
+        "Latest finalized slot of the chain."
+    ).unwrap();
+    pub static ref SLOT_LAG: IntGauge = register_int_gauge!(
+        "solistener_slot_lag",
+        "Slots between the latest finalized slot and the processed slot."
+    ).unwrap();
+    pub static ref BLOCKS_INGESTED: IntCounter = register_int_counter!(
+        "solistener_blocks_ingested_total",
+        "Blocks written to the sink."
+    ).unwrap();
+    pub static ref TRANSACTIONS_INGESTED: IntCounter = register_int_counter!(
+        "solistener_transactions_ingested_total",
+        "Transactions written to the sink."
+    ).unwrap();
+    pub static ref RPC_ERRORS: IntCounterVec = register_int_counter_vec!(
+        "solistener_rpc_errors_total",
+        "Failed RPC requests.",
+        &["endpoint", "method"]
+    ).unwrap();
+    pub static ref RPC_LATENCY: HistogramVec = register_histogram_vec!(
+        "solistener_rpc_latency_seconds",
+        "Latency of RPC requests.",
+        &["endpoint", "method"]
+    ).unwrap();
+    pub static ref INSERT_RETRIES: IntCounterVec = register_int_counter_vec!(
+        "solistener_insert_retries_total",
+        "Retried sink inserts.",
+        &["table"]
+    ).unwrap();
+    pub static ref INSERT_FAILURES: IntCounterVec = register_int_counter_vec!(
+        "solistener_insert_failures_total",
+        "Sink inserts that failed after all attempts.",
+        &["table"]
+    ).unwrap();
+    pub static ref BATCH_FLUSHES: IntCounterVec = register_int_counter_vec!(
+        "solistener_batch_flushes_total",
+        "Batches of rows written to the sink, by what triggered the write.",
+        &["reason"]
+    ).unwrap();
+    pub static ref BLOCKHASH_MISMATCHES: IntCounter = register_int_counter!(
+        "solistener_blockhash_mismatches_total",
+        "Blocks whose previous blockhash is not the hash of the previous block."
+    ).unwrap();
+    pub static ref ACTIVE_PROCESSORS: IntGauge = register_int_gauge!(
+        "solistener_active_processors",
+        "Block processors currently running."
+    ).unwrap();
+    pub static ref PROCESSOR_LIMIT: IntGauge = register_int_gauge!(
+        "solistener_processor_limit",
+        "Block processors allowed to run."
+    ).unwrap();
+    pub static ref ACTIVE_BLOCK_REQUESTS: IntGauge = register_int_gauge!(
+        "solistener_active_block_requests",
+        "getBlock requests in flight."
+    ).unwrap();
+    pub static ref BLOCK_REQUEST_LIMIT: IntGauge = register_int_gauge!(
+        "solistener_block_request_limit",
+        "getBlock requests allowed in flight, adapted to the RPC outcomes."
+    ).unwrap();
+}
+
+/// Render all registered metrics in the Prometheus text format.
+pub fn gather() -> (String, Vec<u8>) {
+    let encoder = TextEncoder::new();
+    let mut buffer: Vec<u8> = Vec::new();
+    if let Err(err) = encoder.encode(&prometheus::gather(), &mut buffer) {
+        error!(error = ?err, "Failed to encode metrics.");
+    }
+    return (encoder.format_type().to_string(), buffer);
+}
@@ -0,0 +1,152 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        mpsc::{
+            self,
+            Receiver,
+            Sender,
+        },
+        Arc,
+        Mutex,
+    },
+    thread,
+    time::{
+        self,
+        Instant,
+    },
+};
+
+use solana_sdk::clock::Slot;
+use solana_transaction_status::{
+    EncodedConfirmedBlock,
+    UiTransactionEncoding,
+};
+use tracing::warn;
+
+use crate::{
+    concurrency::Concurrency,
+    config::Config,
+    solana_rpc::{
+        self,
+        SolanaRpc,
+    },
+};
+
+/// Fetches blocks ahead of the processing with a pool of workers,
+/// each with its own RPC client. Blocks are handed back in slot order.
+/// The requests in flight adapt to the latency and errors of the endpoints.
+pub struct Prefetcher {
+    //Dropped with the prefetcher, which stops the workers
+    request_sender: Sender<Slot>,
+    block_receiver: Receiver<(Slot, EncodedConfirmedBlock)>,
+    window: usize,
+}
+
+fn get_block(solana_client: &SolanaRpc, requests: &Concurrency, slot: Slot) -> EncodedConfirmedBlock {
+    let mut period = time::Duration::from_millis(100);
+    loop {
+        //Released before the backoff so that other workers may retry sooner
+        let permit = requests.acquire();
+        let start = Instant::now();
+        let block_result = solana_client.get_block_with_encoding(
+            slot, UiTransactionEncoding::Base64);
+        requests.record_rpc(start.elapsed(), block_result.is_ok());
+        drop(permit);
+        if let Ok(block) = block_result {
+            return block;
+        }
+        warn!(slot, retry_ms = period.as_millis() as u64, "Attempt to get block failed. Retry.");
+        solana_rpc::record_failed_retry(period);
+        thread::sleep(period);
+        //Use exponential backoff
+        period *= 2;
+    }
+}
+
+impl Prefetcher {
+    pub fn new(config: &Config) -> Prefetcher {
+        let requests = Concurrency::block_requests(config);
+        let (request_sender, request_receiver) = mpsc::channel::<Slot>();
+        let (block_sender, block_receiver) = mpsc::channel();
+        let request_receiver = Arc::new(Mutex::new(request_receiver));
+        for _ in 0..config.prefetch_window {
+            let request_receiver = Arc::clone(&request_receiver);
+            let block_sender = block_sender.clone();
+            let requests = requests.clone();
+            let solana_client = SolanaRpc::new(&config.rpc_endpoints);
+            thread::spawn(move || {
+                loop {
+                    let request = request_receiver.lock().unwrap().recv();
+                    match request {
+                        Ok(slot) => {
+                            let block = get_block(&solana_client, &requests, slot);
+                            if block_sender.send((slot, block)).is_err() {
+                                return;
+                            }
+                        }
+                        Err(_) => {
+                            return;
+                        }
+                    }
+                }
+            });
+        }
+        Prefetcher {
+            request_sender: request_sender,
+            block_receiver: block_receiver,
+            window: config.prefetch_window,
+        }
+    }
+
+    /// Blocks of the slots in order, with up to the prefetch window of
+    /// them fetched concurrently. Must be consumed to the end before the
+    /// next fetch, since the blocks still in flight are not discarded.
+    pub fn fetch(&self, slots: Vec<Slot>) -> Blocks {
+        Blocks {
+            prefetcher: self,
+            slots: slots,
+            next_request: 0,
+            next_slot: 0,
+            fetched: HashMap::new(),
+        }
+    }
+}
+
+pub struct Blocks<'a> {
+    prefetcher: &'a Prefetcher,
+    slots: Vec<Slot>,
+    //Index of the next slot to request from the workers
+    next_request: usize,
+    //Index of the next slot to hand back
+    next_slot: usize,
+    //Blocks fetched ahead of the next slot
+    fetched: HashMap<Slot, EncodedConfirmedBlock>,
+}
+
+impl<'a> Iterator for Blocks<'a> {
+    type Item = (Slot, EncodedConfirmedBlock);
+
+    fn next(&mut self) -> Option<(Slot, EncodedConfirmedBlock)> {
+        if self.next_slot >= self.slots.len() {
+            return None;
+        }
+        while self.next_request < self.slots.len()
+            && self.next_request - self.next_slot < self.prefetcher.window {
+            self.prefetcher.request_sender
+                .send(self.slots[self.next_request])
+                .expect("Prefetch workers stopped");
+            self.next_request += 1;
+        }
+        let slot = self.slots[self.next_slot];
+        loop {
+            if let Some(block) = self.fetched.remove(&slot) {
+                self.next_slot += 1;
+                return Some((slot, block));
+            }
+            let (fetched_slot, block) = self.prefetcher.block_receiver
+                .recv()
+                .expect("Prefetch workers stopped");
+            self.fetched.insert(fetched_slot, block);
+        }
+    }
+}
@@ -0,0 +1,134 @@
+use chrono::DateTime;
+use gcp_bigquery_client::model::table_field_schema::TableFieldSchema;
+use prost::encoding;
+use prost_types::{
+    field_descriptor_proto::{
+        Label,
+        Type,
+    },
+    DescriptorProto,
+    FieldDescriptorProto,
+};
+use serde_json::Value;
+
+use crate::schema::{
+    self,
+    REPEATED,
+};
+
+fn message_name(field_name: &str) -> String {
+    format!("{}Record", field_name)
+}
+
+fn nested_fields(field: &TableFieldSchema) -> &[TableFieldSchema] {
+    field.fields.as_deref().unwrap_or(&[])
+}
+
+/// Self contained proto2 descriptor of a row, with the descriptors
+/// of its records nested in it. Field numbers follow the column order.
+pub fn descriptor(name: &str, fields: &[TableFieldSchema]) -> DescriptorProto {
+    let mut message = DescriptorProto {
+        name: Some(name.to_string()),
+        ..Default::default()
+    };
+    for (index, field) in fields.iter().enumerate() {
+        let label: Label;
+        if schema::mode_name(field) == REPEATED {
+            label = Label::Repeated;
+        } else {
+            label = Label::Optional;
+        }
+        let mut field_descriptor = FieldDescriptorProto {
+            name: Some(field.name.clone()),
+            number: Some(index as i32 + 1),
+            label: Some(label as i32),
+            ..Default::default()
+        };
+        let field_type = match schema::type_name(field).as_str() {
+            //Timestamps are written as microseconds since the epoch
+            "INTEGER" | "TIMESTAMP" => Type::Int64,
+            "BOOLEAN" => Type::Bool,
+            "FLOAT" => Type::Double,
+            "BYTES" => Type::Bytes,
+            "RECORD" => {
+                let nested_name = message_name(&field.name);
+                message.nested_type.push(descriptor(&nested_name, nested_fields(field)));
+                field_descriptor.type_name = Some(nested_name);
+                Type::Message
+            }
+            //STRING and NUMERIC, which is converted from its decimal string
+            _ => Type::String,
+        };
+        field_descriptor.r#type = Some(field_type as i32);
+        message.field.push(field_descriptor);
+    }
+    return message;
+}
+
+fn encode_value(
+    tag: u32,
+    field: &TableFieldSchema,
+    value: &Value,
+    buffer: &mut Vec<u8>,
+) -> Result<(), String> {
+    let invalid = || format!("Invalid value {} for column {}", value, field.name);
+    match schema::type_name(field).as_str() {
+        "INTEGER" => {
+            let number = value.as_i64().ok_or_else(invalid)?;
+            encoding::int64::encode(tag, &number, buffer);
+        }
+        "BOOLEAN" => {
+            let flag = value.as_bool().ok_or_else(invalid)?;
+            encoding::bool::encode(tag, &flag, buffer);
+        }
+        "FLOAT" => {
+            let number = value.as_f64().ok_or_else(invalid)?;
+            encoding::double::encode(tag, &number, buffer);
+        }
+        "TIMESTAMP" => {
+            let timestamp = DateTime::parse_from_rfc3339(value.as_str().ok_or_else(invalid)?)
+                .map_err(|_| invalid())?;
+            let micros = timestamp.timestamp() * 1_000_000
+                + timestamp.timestamp_subsec_micros() as i64;
+            encoding::int64::encode(tag, &micros, buffer);
+        }
+        "BYTES" => {
+            let bytes = base64::decode(value.as_str().ok_or_else(invalid)?)
+                .map_err(|_| invalid())?;
+            encoding::bytes::encode(tag, &bytes, buffer);
+        }
+        "RECORD" => {
+            //Embedded messages share the wire format of bytes
+            let nested = encode(nested_fields(field), value)?;
+            encoding::bytes::encode(tag, &nested, buffer);
+        }
+        _ => {
+            let text = value.as_str().ok_or_else(invalid)?.to_string();
+            encoding::string::encode(tag, &text, buffer);
+        }
+    }
+    Ok(())
+}
+
+/// Encode a row serialized to JSON as a message of its descriptor.
+pub fn encode(fields: &[TableFieldSchema], row: &Value) -> Result<Vec<u8>, String> {
+    let object = row
+        .as_object()
+        .ok_or_else(|| format!("Row {} is not an object", row))?;
+    let mut buffer: Vec<u8> = Vec::new();
+    for (index, field) in fields.iter().enumerate() {
+        let tag = index as u32 + 1;
+        match object.get(&field.name) {
+            None | Some(Value::Null) => {}
+            Some(Value::Array(values)) => {
+                for value in values {
+                    encode_value(tag, field, value, &mut buffer)?;
+                }
+            }
+            Some(value) => {
+                encode_value(tag, field, value, &mut buffer)?;
+            }
+        }
+    }
+    return Ok(buffer);
+}
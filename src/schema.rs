@@ -0,0 +1,653 @@
+use chrono::{
+    DateTime,
+    Utc,
+};
+use gcp_bigquery_client::model::table_field_schema::TableFieldSchema;
+use serde::Serialize;
+
+use crate::{
+    balance_change::BalanceChange,
+    block::Block,
+    epoch::{
+        EpochSummary,
+        InflationReward,
+    },
+    slot_status::SlotStatus,
+    transaction::Transaction,
+    transfer::Transfer,
+    vote::Vote,
+};
+
+pub const TRANSACTIONS_TABLE_ID: &str = "transactions";
+pub const BLOCKS_TABLE_ID: &str = "blocks";
+pub const TRANSFERS_TABLE_ID: &str = "transfers";
+pub const BALANCE_CHANGES_TABLE_ID: &str = "balance_changes";
+pub const VOTES_TABLE_ID: &str = "votes";
+pub const SLOTS_TABLE_ID: &str = "slots";
+pub const EPOCHS_TABLE_ID: &str = "epochs";
+pub const INFLATION_REWARDS_TABLE_ID: &str = "inflation_rewards";
+
+pub(crate) const REPEATED: &str = "REPEATED";
+const BLOCK_TIMESTAMP: &str = "block_timestamp";
+//Limit set by BigQuery
+const MAX_CLUSTERING_FIELDS: usize = 4;
+
+/// A table written by the listener and its expected schema.
+pub struct TableDefinition {
+    pub table_id: &'static str,
+    pub friendly_name: &'static str,
+    pub description: &'static str,
+    pub fields: Vec<TableFieldSchema>,
+    pub partition_field: &'static str,
+    //Default clustering columns, most selective lookups first
+    pub clustering: &'static [&'static str],
+}
+
+/// Rust type of a column. Maps the type to a BigQuery field.
+pub trait ColumnType {
+    fn field(name: &str) -> TableFieldSchema;
+
+    //Value with every optional, repeated and nested field filled in
+    #[cfg(test)]
+    fn sample() -> Self where Self: Sized;
+}
+
+/// Row, or nested record, whose fields are derived from its struct.
+/// Implemented by the bigquery_record macro.
+pub trait Record {
+    fn fields() -> Vec<TableFieldSchema>;
+}
+
+/// Decimal amount serialized as a string, a NUMERIC column.
+#[derive(Clone, Default, Serialize)]
+#[serde(transparent)]
+pub struct Numeric(pub String);
+
+/// Base64 encoded buffer, a BYTES column.
+#[derive(Clone, Default, Serialize)]
+#[serde(transparent)]
+pub struct Base64(pub String);
+
+/// Declare a struct written to BigQuery and derive its schema from
+/// the types of its fields, so the two cannot drift apart.
+#[macro_export]
+macro_rules! bigquery_record {
+    (
+        $(#[$meta:meta])*
+        $vis:vis struct $name:ident {
+            $(
+                $(#[$field_meta:meta])*
+                $field_vis:vis $field:ident : $ty:ty
+            ),* $(,)?
+        }
+    ) => {
+        $(#[$meta])*
+        $vis struct $name {
+            $(
+                $(#[$field_meta])*
+                $field_vis $field: $ty,
+            )*
+        }
+
+        impl $crate::schema::Record for $name {
+            fn fields() -> Vec<gcp_bigquery_client::model::table_field_schema::TableFieldSchema> {
+                vec![
+                    $(
+                        <$ty as $crate::schema::ColumnType>::field(stringify!($field)),
+                    )*
+                ]
+            }
+        }
+
+        impl $crate::schema::ColumnType for $name {
+            fn field(name: &str) -> gcp_bigquery_client::model::table_field_schema::TableFieldSchema {
+                gcp_bigquery_client::model::table_field_schema::TableFieldSchema::record(
+                    name,
+                    <$name as $crate::schema::Record>::fields(),
+                )
+            }
+
+            #[cfg(test)]
+            fn sample() -> $name {
+                $name {
+                    $(
+                        $field: <$ty as $crate::schema::ColumnType>::sample(),
+                    )*
+                }
+            }
+        }
+    };
+}
+
+macro_rules! column_type {
+    ($constructor:ident, $($ty:ty),*) => {
+        $(
+            impl ColumnType for $ty {
+                fn field(name: &str) -> TableFieldSchema {
+                    TableFieldSchema::$constructor(name)
+                }
+
+                #[cfg(test)]
+                fn sample() -> $ty {
+                    Default::default()
+                }
+            }
+        )*
+    };
+}
+
+column_type!(integer, u8, u32, u64, i64);
+column_type!(float, f64);
+column_type!(bool, bool);
+column_type!(string, String);
+
+impl ColumnType for DateTime<Utc> {
+    fn field(name: &str) -> TableFieldSchema {
+        TableFieldSchema::timestamp(name)
+    }
+
+    #[cfg(test)]
+    fn sample() -> DateTime<Utc> {
+        chrono::TimeZone::timestamp(&Utc, 0, 0)
+    }
+}
+
+column_type!(numeric, Numeric);
+column_type!(bytes, Base64);
+
+//Columns are NULLABLE unless set otherwise
+impl<T: ColumnType> ColumnType for Option<T> {
+    fn field(name: &str) -> TableFieldSchema {
+        T::field(name)
+    }
+
+    #[cfg(test)]
+    fn sample() -> Option<T> {
+        Some(T::sample())
+    }
+}
+
+impl<T: ColumnType> ColumnType for Vec<T> {
+    fn field(name: &str) -> TableFieldSchema {
+        let mut field = T::field(name);
+        field.mode = Some(REPEATED.to_string());
+        field
+    }
+
+    #[cfg(test)]
+    fn sample() -> Vec<T> {
+        vec![T::sample()]
+    }
+}
+
+fn transactions() -> TableDefinition {
+    TableDefinition {
+        table_id: TRANSACTIONS_TABLE_ID,
+        friendly_name: "Transactions",
+        description: "Solana ledger transactions",
+        fields: Transaction::fields(),
+        partition_field: BLOCK_TIMESTAMP,
+        clustering: &["transaction_id"],
+    }
+}
+
+fn blocks() -> TableDefinition {
+    TableDefinition {
+        table_id: BLOCKS_TABLE_ID,
+        friendly_name: "Blocks",
+        description: "Solana ledger blocks",
+        fields: Block::fields(),
+        partition_field: BLOCK_TIMESTAMP,
+        clustering: &["slot"],
+    }
+}
+
+fn transfers() -> TableDefinition {
+    TableDefinition {
+        table_id: TRANSFERS_TABLE_ID,
+        friendly_name: "Transfers",
+        description: "SOL and SPL token transfers",
+        fields: Transfer::fields(),
+        partition_field: BLOCK_TIMESTAMP,
+        clustering: &["program_id", "transaction_id"],
+    }
+}
+
+fn balance_changes() -> TableDefinition {
+    TableDefinition {
+        table_id: BALANCE_CHANGES_TABLE_ID,
+        friendly_name: "Balance changes",
+        description: "SOL and SPL token balance changes per account",
+        fields: BalanceChange::fields(),
+        partition_field: BLOCK_TIMESTAMP,
+        clustering: &["account", "transaction_id"],
+    }
+}
+
+fn votes() -> TableDefinition {
+    TableDefinition {
+        table_id: VOTES_TABLE_ID,
+        friendly_name: "Votes",
+        description: "Solana validator votes",
+        fields: Vote::fields(),
+        partition_field: BLOCK_TIMESTAMP,
+        clustering: &["vote_account", "slot"],
+    }
+}
+
+fn slots() -> TableDefinition {
+    TableDefinition {
+        table_id: SLOTS_TABLE_ID,
+        friendly_name: "Slots",
+        description: "Solana slots, produced or skipped, and their leader",
+        fields: SlotStatus::fields(),
+        partition_field: BLOCK_TIMESTAMP,
+        clustering: &["leader", "slot"],
+    }
+}
+
+fn epochs() -> TableDefinition {
+    TableDefinition {
+        table_id: EPOCHS_TABLE_ID,
+        friendly_name: "Epochs",
+        description: "Solana epochs and their inflation rate",
+        fields: EpochSummary::fields(),
+        partition_field: BLOCK_TIMESTAMP,
+        clustering: &["epoch"],
+    }
+}
+
+fn inflation_rewards() -> TableDefinition {
+    TableDefinition {
+        table_id: INFLATION_REWARDS_TABLE_ID,
+        friendly_name: "Inflation rewards",
+        description: "Solana inflation rewards of the stake accounts, per epoch",
+        fields: InflationReward::fields(),
+        partition_field: BLOCK_TIMESTAMP,
+        clustering: &["stake_account", "epoch"],
+    }
+}
+
+/// All tables written by the listener and the epochs job.
+pub fn tables() -> Vec<TableDefinition> {
+    vec![
+        transactions(),
+        blocks(),
+        transfers(),
+        balance_changes(),
+        votes(),
+        slots(),
+        epochs(),
+        inflation_rewards(),
+    ]
+}
+
+/// Check that the clustering columns are top level, non repeated
+/// columns of the table.
+pub fn validate_clustering(definition: &TableDefinition, clustering: &[String]) -> Result<(), String> {
+    if clustering.len() > MAX_CLUSTERING_FIELDS {
+        return Err(format!(
+            "Table {} is clustered by {} columns, at most {} are allowed",
+            definition.table_id,
+            clustering.len(),
+            MAX_CLUSTERING_FIELDS,
+        ));
+    }
+    for name in clustering {
+        let field = definition.fields.iter().find(|field| &field.name == name);
+        match field {
+            None => {
+                return Err(format!("Table {} has no column {} to cluster by", definition.table_id, name));
+            }
+            Some(field) => {
+                if mode_name(field) == REPEATED || type_name(field) == "RECORD" {
+                    return Err(format!(
+                        "Table {} cannot be clustered by the repeated or record column {}",
+                        definition.table_id,
+                        name,
+                    ));
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Difference between the expected schema and the schema of a table.
+#[derive(Default)]
+pub struct SchemaDrift {
+    //Paths of the fields missing from the table
+    pub added: Vec<String>,
+    //Fields whose type or mode changed. They need a manual migration.
+    pub incompatible: Vec<String>,
+}
+
+impl SchemaDrift {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.incompatible.is_empty()
+    }
+}
+
+//Type name with the aliases BigQuery returns resolved
+pub(crate) fn type_name(field: &TableFieldSchema) -> String {
+    let name = format!("{:?}", field.r#type).to_uppercase();
+    match name.as_str() {
+        "INT64" => "INTEGER".to_string(),
+        "FLOAT64" => "FLOAT".to_string(),
+        "BOOL" => "BOOLEAN".to_string(),
+        "STRUCT" => "RECORD".to_string(),
+        _ => name,
+    }
+}
+
+pub(crate) fn mode_name(field: &TableFieldSchema) -> String {
+    field.mode.clone().unwrap_or_else(|| "NULLABLE".to_string()).to_uppercase()
+}
+
+fn compare_fields(
+    path: &str,
+    expected: &[TableFieldSchema],
+    actual: &[TableFieldSchema],
+    drift: &mut SchemaDrift,
+) {
+    for expected_field in expected {
+        let field_path = format!("{}{}", path, expected_field.name);
+        let actual_field = actual
+            .iter()
+            .find(|field| field.name.eq_ignore_ascii_case(&expected_field.name));
+        match actual_field {
+            None => {
+                drift.added.push(field_path);
+            }
+            Some(actual_field) => {
+                if type_name(expected_field) != type_name(actual_field)
+                    || mode_name(expected_field) != mode_name(actual_field) {
+                    drift.incompatible.push(format!(
+                        "{} is {} {} but expected {} {}",
+                        field_path,
+                        mode_name(actual_field),
+                        type_name(actual_field),
+                        mode_name(expected_field),
+                        type_name(expected_field),
+                    ));
+                    continue;
+                }
+                if let (Some(expected_fields), Some(actual_fields)) =
+                    (&expected_field.fields, &actual_field.fields) {
+                    compare_fields(
+                        &format!("{}.", field_path),
+                        expected_fields,
+                        actual_fields,
+                        drift,
+                    );
+                }
+            }
+        }
+    }
+}
+
+/// Compare the expected fields with the fields of an existing table.
+/// Fields of the table that are no longer expected are left alone.
+pub fn detect_drift(expected: &[TableFieldSchema], actual: &[TableFieldSchema]) -> SchemaDrift {
+    let mut drift = SchemaDrift::default();
+    compare_fields("", expected, actual, &mut drift);
+    return drift;
+}
+
+/// Add the expected fields that are missing to the fields of a table,
+/// including the ones nested in records. Existing fields keep their order.
+pub fn merge_fields(expected: &[TableFieldSchema], actual: &[TableFieldSchema]) -> Vec<TableFieldSchema> {
+    let mut merged = actual.to_vec();
+    for expected_field in expected {
+        let position = merged
+            .iter()
+            .position(|field| field.name.eq_ignore_ascii_case(&expected_field.name));
+        match position {
+            None => {
+                merged.push(expected_field.clone());
+            }
+            Some(index) => {
+                if let (Some(expected_fields), Some(actual_fields)) =
+                    (&expected_field.fields, &merged[index].fields) {
+                    let fields = merge_fields(expected_fields, actual_fields);
+                    merged[index].fields = Some(fields);
+                }
+            }
+        }
+    }
+    return merged;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Columns of the fields as "path TYPE MODE", nested fields included
+    fn columns(path: &str, fields: &[TableFieldSchema]) -> Vec<String> {
+        let mut paths: Vec<String> = Vec::new();
+        for field in fields {
+            let field_path = format!("{}{}", path, field.name);
+            paths.push(format!("{} {} {}", field_path, type_name(field), mode_name(field)));
+            if let Some(nested_fields) = &field.fields {
+                paths.extend(columns(&format!("{}.", field_path), nested_fields));
+            }
+        }
+        return paths;
+    }
+
+    fn definition(table_id: &str) -> TableDefinition {
+        return tables()
+            .into_iter()
+            .find(|definition| definition.table_id == table_id)
+            .expect("Table is not defined");
+    }
+
+    // Paths of the keys of a serialized row, nested records included
+    fn keys(path: &str, value: &serde_json::Value) -> Vec<String> {
+        let mut paths: Vec<String> = Vec::new();
+        let object = match value.as_object() {
+            Some(object) => object,
+            None => return paths,
+        };
+        for (key, value) in object {
+            let key_path = format!("{}{}", path, key);
+            paths.push(key_path.clone());
+            //Repeated records are checked through their first row
+            let nested = match value {
+                serde_json::Value::Array(values) => values.first(),
+                value => Some(value),
+            };
+            if let Some(nested) = nested {
+                paths.extend(keys(&format!("{}.", key_path), nested));
+            }
+        }
+        return paths;
+    }
+
+    // Check the schema of the row struct and the table created by setupbq
+    // against the expected columns, and the keys of a serialized row
+    // against the expected column names
+    fn check_record<T: Record + ColumnType + Serialize>(table_id: &str, expected: &[&str]) {
+        let expected: Vec<String> = expected.iter().map(|column| column.to_string()).collect();
+        assert_eq!(columns("", &T::fields()), expected, "schema of {}", table_id);
+        assert_eq!(columns("", &definition(table_id).fields), expected, "table {}", table_id);
+
+        let row = serde_json::to_value(T::sample()).unwrap();
+        assert!(row.is_object(), "A {} row is not serialized as an object", table_id);
+        let mut row_keys = keys("", &row);
+        row_keys.sort();
+        let mut names: Vec<String> = expected
+            .iter()
+            .map(|column| column.split(' ').next().unwrap().to_string())
+            .collect();
+        names.sort();
+        assert_eq!(row_keys, names, "keys of a {} row", table_id);
+    }
+
+    #[test]
+    fn every_table_has_a_record() {
+        let table_ids: Vec<&str> = tables().iter().map(|definition| definition.table_id).collect();
+        assert_eq!(table_ids, vec![
+            TRANSACTIONS_TABLE_ID,
+            BLOCKS_TABLE_ID,
+            TRANSFERS_TABLE_ID,
+            BALANCE_CHANGES_TABLE_ID,
+            VOTES_TABLE_ID,
+            SLOTS_TABLE_ID,
+            EPOCHS_TABLE_ID,
+            INFLATION_REWARDS_TABLE_ID,
+        ]);
+    }
+
+    #[test]
+    fn transactions_schema() {
+        check_record::<Transaction>(TRANSACTIONS_TABLE_ID, &[
+            "block_timestamp TIMESTAMP NULLABLE",
+            "slot INTEGER NULLABLE",
+            "transaction_id STRING NULLABLE",
+            "is_successful BOOLEAN NULLABLE",
+            "error STRING NULLABLE",
+            "fee INTEGER NULLABLE",
+            "base_fee INTEGER NULLABLE",
+            "priority_fee INTEGER NULLABLE",
+            "compute_unit_price INTEGER NULLABLE",
+            "compute_unit_limit INTEGER NULLABLE",
+            "compute_units_consumed INTEGER NULLABLE",
+            "accounts RECORD REPEATED",
+            "accounts.address STRING NULLABLE",
+            "accounts.pre_sol_balance INTEGER NULLABLE",
+            "accounts.post_sol_balance INTEGER NULLABLE",
+            "accounts.pre_token_balances RECORD REPEATED",
+            "accounts.pre_token_balances.mint STRING NULLABLE",
+            "accounts.pre_token_balances.amount NUMERIC NULLABLE",
+            "accounts.post_token_balances RECORD REPEATED",
+            "accounts.post_token_balances.mint STRING NULLABLE",
+            "accounts.post_token_balances.amount NUMERIC NULLABLE",
+            "instructions RECORD REPEATED",
+            "instructions.program_id STRING NULLABLE",
+            "instructions.accounts STRING REPEATED",
+            "instructions.data BYTES NULLABLE",
+            "instructions.invocations RECORD REPEATED",
+            "instructions.invocations.program_id STRING NULLABLE",
+            "instructions.invocations.depth INTEGER NULLABLE",
+            "instructions.invocations.is_successful BOOLEAN NULLABLE",
+            "instructions.invocations.error STRING NULLABLE",
+            "instructions.invocations.compute_units_consumed INTEGER NULLABLE",
+            "instructions.invocations.compute_units_limit INTEGER NULLABLE",
+            "instructions.invocations.logs STRING REPEATED",
+            "instructions.invocations.events BYTES REPEATED",
+            "log_messages STRING REPEATED",
+        ]);
+    }
+
+    #[test]
+    fn blocks_schema() {
+        check_record::<Block>(BLOCKS_TABLE_ID, &[
+            "block_timestamp TIMESTAMP NULLABLE",
+            "slot INTEGER NULLABLE",
+            "parent_slot INTEGER NULLABLE",
+            "blockhash STRING NULLABLE",
+            "previous_blockhash STRING NULLABLE",
+            "leader STRING NULLABLE",
+            "parent_blockhash_matches BOOLEAN NULLABLE",
+            "transaction_count INTEGER NULLABLE",
+            "successful_transaction_count INTEGER NULLABLE",
+            "failed_transaction_count INTEGER NULLABLE",
+            "vote_transaction_count INTEGER NULLABLE",
+            "non_vote_transaction_count INTEGER NULLABLE",
+            "total_fee INTEGER NULLABLE",
+            "total_compute_units INTEGER NULLABLE",
+            "unique_signer_count INTEGER NULLABLE",
+            "rewards RECORD REPEATED",
+            "rewards.pubkey STRING NULLABLE",
+            "rewards.lamports INTEGER NULLABLE",
+            "rewards.post_balance INTEGER NULLABLE",
+            "rewards.reward_type STRING NULLABLE",
+        ]);
+    }
+
+    #[test]
+    fn transfers_schema() {
+        check_record::<Transfer>(TRANSFERS_TABLE_ID, &[
+            "block_timestamp TIMESTAMP NULLABLE",
+            "slot INTEGER NULLABLE",
+            "transaction_id STRING NULLABLE",
+            "instruction_index INTEGER NULLABLE",
+            "inner_instruction_index INTEGER NULLABLE",
+            "program_id STRING NULLABLE",
+            "transfer_type STRING NULLABLE",
+            "source STRING NULLABLE",
+            "destination STRING NULLABLE",
+            "mint STRING NULLABLE",
+            "amount NUMERIC NULLABLE",
+        ]);
+    }
+
+    #[test]
+    fn balance_changes_schema() {
+        check_record::<BalanceChange>(BALANCE_CHANGES_TABLE_ID, &[
+            "block_timestamp TIMESTAMP NULLABLE",
+            "slot INTEGER NULLABLE",
+            "transaction_id STRING NULLABLE",
+            "account STRING NULLABLE",
+            "mint STRING NULLABLE",
+            "pre_balance NUMERIC NULLABLE",
+            "post_balance NUMERIC NULLABLE",
+            "delta NUMERIC NULLABLE",
+        ]);
+    }
+
+    #[test]
+    fn votes_schema() {
+        check_record::<Vote>(VOTES_TABLE_ID, &[
+            "block_timestamp TIMESTAMP NULLABLE",
+            "slot INTEGER NULLABLE",
+            "transaction_id STRING NULLABLE",
+            "is_successful BOOLEAN NULLABLE",
+            "validator STRING NULLABLE",
+            "vote_account STRING NULLABLE",
+            "voted_slots INTEGER REPEATED",
+            "vote_hash STRING NULLABLE",
+            "vote_timestamp TIMESTAMP NULLABLE",
+        ]);
+    }
+
+    #[test]
+    fn slots_schema() {
+        check_record::<SlotStatus>(SLOTS_TABLE_ID, &[
+            "block_timestamp TIMESTAMP NULLABLE",
+            "slot INTEGER NULLABLE",
+            "status STRING NULLABLE",
+            "leader STRING NULLABLE",
+        ]);
+    }
+
+    #[test]
+    fn epochs_schema() {
+        check_record::<EpochSummary>(EPOCHS_TABLE_ID, &[
+            "block_timestamp TIMESTAMP NULLABLE",
+            "epoch INTEGER NULLABLE",
+            "first_slot INTEGER NULLABLE",
+            "last_slot INTEGER NULLABLE",
+            "slot_count INTEGER NULLABLE",
+            "inflation_total FLOAT NULLABLE",
+            "inflation_validator FLOAT NULLABLE",
+            "inflation_foundation FLOAT NULLABLE",
+            "reward_count INTEGER NULLABLE",
+            "total_reward INTEGER NULLABLE",
+        ]);
+    }
+
+    #[test]
+    fn inflation_rewards_schema() {
+        check_record::<InflationReward>(INFLATION_REWARDS_TABLE_ID, &[
+            "block_timestamp TIMESTAMP NULLABLE",
+            "epoch INTEGER NULLABLE",
+            "stake_account STRING NULLABLE",
+            "amount INTEGER NULLABLE",
+            "post_balance INTEGER NULLABLE",
+            "effective_slot INTEGER NULLABLE",
+            "commission INTEGER NULLABLE",
+        ]);
+    }
+}
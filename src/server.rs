@@ -0,0 +1,81 @@
+use std::{
+    convert::Infallible,
+    net::SocketAddr,
+    thread,
+};
+
+use hyper::{
+    header::CONTENT_TYPE,
+    service::{
+        make_service_fn,
+        service_fn,
+    },
+    Body,
+    Request,
+    Response,
+    Server,
+    StatusCode,
+};
+use tokio::runtime::Runtime;
+use tracing::{
+    error,
+    info,
+};
+
+use crate::{
+    health::HEALTH,
+    metrics,
+};
+
+fn status_response(status: Result<(), String>) -> hyper::http::Result<Response<Body>> {
+    match status {
+        Ok(()) => {
+            Response::builder()
+                .body(Body::from("ok"))
+        }
+        Err(reason) => {
+            Response::builder()
+                .status(StatusCode::SERVICE_UNAVAILABLE)
+                .body(Body::from(reason))
+        }
+    }
+}
+
+async fn route(request: Request<Body>) -> Result<Response<Body>, Infallible> {
+    let response = match request.uri().path() {
+        "/metrics" => {
+            let (content_type, buffer) = metrics::gather();
+            Response::builder()
+                .header(CONTENT_TYPE, content_type)
+                .body(Body::from(buffer))
+        }
+        "/healthz" => {
+            status_response(HEALTH.check_live())
+        }
+        "/readyz" => {
+            status_response(HEALTH.check_ready())
+        }
+        _ => {
+            Response::builder()
+                .status(StatusCode::NOT_FOUND)
+                .body(Body::empty())
+        }
+    };
+    return Ok(response.unwrap());
+}
+
+/// Serve the HTTP endpoints on a background thread.
+pub fn serve(address: SocketAddr) {
+    thread::spawn(move || {
+        let runtime = Runtime::new().unwrap();
+        runtime.block_on(async {
+            let make_service = make_service_fn(|_| async {
+                Ok::<_, Infallible>(service_fn(route))
+            });
+            info!(%address, "Serve HTTP endpoints.");
+            if let Err(err) = Server::bind(&address).serve(make_service).await {
+                error!(error = ?err, "HTTP server stopped.");
+            }
+        });
+    });
+}
@@ -0,0 +1,49 @@
+use chrono::{
+    DateTime,
+    Utc,
+};
+use serde::Serialize;
+use solana_sdk::clock::Slot;
+
+use crate::bigquery_record;
+
+const PRODUCED: &str = "produced";
+const SKIPPED: &str = "skipped";
+
+bigquery_record! {
+    /// Status of a slot of a processed range, so that skipped slots can
+    /// be told apart from blocks missing from the blocks table.
+    #[derive(Serialize)]
+    pub struct SlotStatus {
+        //Unknown for skipped slots
+        block_timestamp: Option<DateTime<Utc>>,
+        slot: u64,
+        status: String,
+        //Identity of the validator scheduled to produce the block
+        leader: Option<String>,
+    }
+}
+
+impl SlotStatus {
+    pub fn produced(slot: Slot, block_timestamp: Option<DateTime<Utc>>, leader: Option<String>) -> SlotStatus {
+        SlotStatus {
+            block_timestamp: block_timestamp,
+            slot: slot,
+            status: PRODUCED.to_string(),
+            leader: leader,
+        }
+    }
+
+    pub fn skipped(slot: Slot, leader: Option<String>) -> SlotStatus {
+        SlotStatus {
+            block_timestamp: None,
+            slot: slot,
+            status: SKIPPED.to_string(),
+            leader: leader,
+        }
+    }
+
+    pub fn get_slot(&self) -> Slot {
+        self.slot
+    }
+}
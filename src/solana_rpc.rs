@@ -1,67 +1,137 @@
 use std::{thread, time};
+use std::time::Instant;
 
+use tracing::{
+    info,
+    warn,
+};
+
+use solana_account_decoder::UiDataSliceConfig;
 use solana_client::rpc_client::RpcClient;
 use solana_client::client_error::Result as ClientResult;
+use solana_client::rpc_config::{
+    RpcAccountInfoConfig,
+    RpcProgramAccountsConfig,
+};
+use solana_client::rpc_response::{
+    RpcInflationRate,
+    RpcInflationReward,
+    RpcLeaderSchedule,
+};
 use solana_sdk::{
-    clock::Slot,
+    clock::{
+        Epoch,
+        Slot,
+        UnixTimestamp,
+    },
     commitment_config::CommitmentConfig,
+    epoch_schedule::EpochSchedule,
+    pubkey::Pubkey,
 };
 use solana_transaction_status::{
     EncodedConfirmedBlock,
     UiTransactionEncoding,
 };
 
+use crate::health::HEALTH;
+use crate::metrics::{
+    RPC_ERRORS,
+    RPC_LATENCY,
+};
+
 pub struct SolanaRpc {
     rpc_client: RpcClient,
     node_url: String,
+    node_urls: Vec<String>,
 }
 
-const SOLANA_NODE_URL: &str = "https://api.mainnet-beta.solana.com";
-const SERUM_NODE_URL: &str = "https://solana-api.projectserum.com";
 //Time to wait before we try another RPC node
 const NODE_TIMEOUT: time::Duration = time::Duration::from_millis(10000);
 
+/// Mark the RPC nodes unreachable once a request keeps failing past the
+/// node timeout, rather than on every failed attempt.
+pub fn record_failed_retry(period: time::Duration) {
+    if period > NODE_TIMEOUT {
+        HEALTH.set_rpc_reachable(false);
+    }
+}
+
 impl SolanaRpc {
-    pub fn new() -> SolanaRpc {
+    pub fn new(node_urls: &[String]) -> SolanaRpc {
+        let node_url = node_urls[0].clone();
         SolanaRpc {
-            rpc_client: RpcClient::new(SOLANA_NODE_URL.to_string()),
-            node_url: SOLANA_NODE_URL.to_string(),
+            rpc_client: RpcClient::new(node_url.clone()),
+            node_url: node_url,
+            node_urls: node_urls.to_vec(),
+        }
+    }
+
+    //Switch over to the next RPC node in the list
+    fn next_node(&mut self) {
+        let index = self.node_urls
+            .iter()
+            .position(|url| *url == self.node_url)
+            .unwrap_or(0);
+        self.node_url = self.node_urls[(index + 1) % self.node_urls.len()].clone();
+        self.rpc_client = RpcClient::new(self.node_url.clone());
+    }
+
+    //Record latency and errors of an RPC request per endpoint
+    fn observe<T>(&self, method: &str, request: impl FnOnce(&RpcClient) -> ClientResult<T>)
+        -> ClientResult<T> {
+        let labels = [self.node_url.as_str(), method];
+        let start = Instant::now();
+        let result = request(&self.rpc_client);
+        RPC_LATENCY
+            .with_label_values(&labels)
+            .observe(start.elapsed().as_secs_f64());
+        if result.is_err() {
+            RPC_ERRORS.with_label_values(&labels).inc();
         }
+        //Marked unreachable only once the retries of a request run out
+        if result.is_ok() {
+            HEALTH.set_rpc_reachable(true);
+        }
+        return result;
     }
 
     pub fn get_block_with_encoding(&self, slot: Slot, encoding: UiTransactionEncoding)
         -> ClientResult<EncodedConfirmedBlock> {
-        return self.rpc_client.get_block_with_encoding(slot, encoding);
+        return self.observe("getBlock", |client| {
+            client.get_block_with_encoding(slot, encoding)
+        });
     }
 
     pub fn get_blocks(&self, start_slot: Slot, end_slot: Option<Slot>)
         -> ClientResult<Vec<Slot>> {
-        return self.rpc_client.get_blocks(start_slot, end_slot);
+        return self.observe("getBlocks", |client| {
+            client.get_blocks(start_slot, end_slot)
+        });
     }
 
-    pub fn get_latest_slot(&mut self) -> Slot {
+    // Retry the request with exponential backoff until it succeeds, and
+    // switch over to the next RPC node once the backoff exceeds the timeout
+    fn with_retry<T>(&mut self, method: &str, request: impl Fn(&RpcClient) -> ClientResult<T>) -> T {
         let mut period = time::Duration::from_millis(100);
         loop {
-            let slot_result = self.rpc_client.get_slot_with_commitment(CommitmentConfig::finalized());
-            match slot_result {
-                Ok(slot) => {
-                    return slot;
+            match self.observe(method, &request) {
+                Ok(value) => {
+                    return value;
                 }
                 Err(error) => {
-                    println!("Attempt to find the latest finalized slot failed with error: {:?}",
-                        error);
-                    println!("Retrying after {} ms.", period.as_millis());
+                    warn!(
+                        endpoint = %self.node_url,
+                        method,
+                        error = ?error,
+                        retry_ms = period.as_millis() as u64,
+                        "RPC request failed. Retry."
+                    );
                 }
             }
+            record_failed_retry(period);
             if period > NODE_TIMEOUT {
-                if self.node_url == SOLANA_NODE_URL.to_string() {
-                    self.rpc_client = RpcClient::new(SERUM_NODE_URL.to_string());
-                    self.node_url = SERUM_NODE_URL.to_string();
-                } else {
-                    self.rpc_client = RpcClient::new(SOLANA_NODE_URL.to_string());
-                    self.node_url = SOLANA_NODE_URL.to_string();
-                }
-                println!("Try out RPC node {}.", self.node_url);
+                self.next_node();
+                info!(endpoint = %self.node_url, "Try out RPC node.");
                 period = time::Duration::from_millis(100);
                 continue;
             }
@@ -70,4 +140,67 @@ impl SolanaRpc {
             period *= 2;
         }
     }
+
+    pub fn get_latest_slot(&mut self) -> Slot {
+        return self.with_retry("getSlot", |client| {
+            client.get_slot_with_commitment(CommitmentConfig::finalized())
+        });
+    }
+
+    /// Epoch schedule of the cluster, retried until it is fetched.
+    pub fn get_epoch_schedule_with_retry(&mut self) -> EpochSchedule {
+        return self.with_retry("getEpochSchedule", |client| {
+            client.get_epoch_schedule()
+        });
+    }
+
+    /// Block time of the slot, retried until it is fetched.
+    pub fn get_block_time_with_retry(&mut self, slot: Slot) -> UnixTimestamp {
+        return self.with_retry("getBlockTime", |client| {
+            client.get_block_time(slot)
+        });
+    }
+
+    /// Inflation rate of the current epoch, retried until it is fetched.
+    pub fn get_inflation_rate_with_retry(&mut self) -> RpcInflationRate {
+        return self.with_retry("getInflationRate", |client| {
+            client.get_inflation_rate()
+        });
+    }
+
+    /// Inflation rewards of the addresses for the epoch, retried until they are fetched.
+    pub fn get_inflation_reward_with_retry(&mut self, addresses: &[Pubkey], epoch: Epoch)
+        -> Vec<Option<RpcInflationReward>> {
+        return self.with_retry("getInflationReward", |client| {
+            client.get_inflation_reward(addresses, Some(epoch))
+        });
+    }
+
+    /// Addresses of the accounts owned by the program, without their data,
+    /// retried until they are fetched.
+    pub fn get_program_account_addresses_with_retry(&mut self, program_id: &Pubkey) -> Vec<Pubkey> {
+        let config = RpcProgramAccountsConfig {
+            account_config: RpcAccountInfoConfig {
+                data_slice: Some(UiDataSliceConfig {
+                    offset: 0,
+                    length: 0,
+                }),
+                commitment: Some(CommitmentConfig::finalized()),
+                ..RpcAccountInfoConfig::default()
+            },
+            ..RpcProgramAccountsConfig::default()
+        };
+        let accounts = self.with_retry("getProgramAccounts", |client| {
+            client.get_program_accounts_with_config(program_id, config.clone())
+        });
+        return accounts.into_iter().map(|(address, _)| address).collect();
+    }
+
+    /// Leader schedule of the epoch of the slot, retried until it is fetched.
+    /// None if the node does not have the schedule of the epoch.
+    pub fn get_leader_schedule_with_retry(&mut self, slot: Slot) -> Option<RpcLeaderSchedule> {
+        return self.with_retry("getLeaderSchedule", |client| {
+            client.get_leader_schedule(Some(slot))
+        });
+    }
 }
@@ -0,0 +1,526 @@
+use std::{
+    collections::HashMap,
+    str::FromStr,
+    sync::Mutex,
+};
+
+use futures::stream;
+use prost_types::DescriptorProto;
+use serde::{
+    Deserialize,
+    Serialize,
+};
+use tonic::{
+    client::Grpc,
+    codec::ProstCodec,
+    codegen::http::uri::PathAndQuery,
+    metadata::MetadataValue,
+    transport::{
+        Channel,
+        ClientTlsConfig,
+    },
+    Request,
+};
+
+use crate::{
+    gcp_auth::{
+        self,
+        GcpAuthenticator,
+    },
+    proto_row,
+    schema::Record,
+};
+
+use proto::{
+    append_rows_request,
+    append_rows_response,
+    AppendRowsRequest,
+    AppendRowsResponse,
+    BatchCommitWriteStreamsRequest,
+    BatchCommitWriteStreamsResponse,
+    CreateWriteStreamRequest,
+    FinalizeWriteStreamRequest,
+    FinalizeWriteStreamResponse,
+    ProtoData,
+    ProtoRows,
+    ProtoSchema,
+    WriteStream,
+    WriteStreamType,
+};
+
+const ENDPOINT: &str = "https://bigquerystorage.googleapis.com";
+const DOMAIN: &str = "bigquerystorage.googleapis.com";
+const CREATE_WRITE_STREAM: &str = "/google.cloud.bigquery.storage.v1.BigQueryWrite/CreateWriteStream";
+const APPEND_ROWS: &str = "/google.cloud.bigquery.storage.v1.BigQueryWrite/AppendRows";
+const FINALIZE_WRITE_STREAM: &str = "/google.cloud.bigquery.storage.v1.BigQueryWrite/FinalizeWriteStream";
+const BATCH_COMMIT_WRITE_STREAMS: &str = "/google.cloud.bigquery.storage.v1.BigQueryWrite/BatchCommitWriteStreams";
+//AppendRows requests are limited to 10MB, keep room for the schema
+const MAX_REQUEST_BYTES: usize = 9 * 1024 * 1024;
+//Status code of an append at an offset that was already written
+const ALREADY_EXISTS: i32 = 6;
+
+//Messages of the google.cloud.bigquery.storage.v1 API used by the writer
+mod proto {
+    use prost_types::{
+        DescriptorProto,
+        Timestamp,
+    };
+
+    #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, prost::Enumeration)]
+    #[repr(i32)]
+    pub enum WriteStreamType {
+        Unspecified = 0,
+        Committed = 1,
+        Pending = 2,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct WriteStream {
+        #[prost(string, tag = "1")]
+        pub name: String,
+        #[prost(enumeration = "WriteStreamType", tag = "2")]
+        pub r#type: i32,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct CreateWriteStreamRequest {
+        #[prost(string, tag = "1")]
+        pub parent: String,
+        #[prost(message, optional, tag = "2")]
+        pub write_stream: Option<WriteStream>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct ProtoSchema {
+        #[prost(message, optional, tag = "1")]
+        pub proto_descriptor: Option<DescriptorProto>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct ProtoRows {
+        #[prost(bytes, repeated, tag = "1")]
+        pub serialized_rows: Vec<Vec<u8>>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct ProtoData {
+        #[prost(message, optional, tag = "1")]
+        pub writer_schema: Option<ProtoSchema>,
+        #[prost(message, optional, tag = "2")]
+        pub rows: Option<ProtoRows>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct AppendRowsRequest {
+        #[prost(string, tag = "1")]
+        pub write_stream: String,
+        #[prost(message, optional, tag = "2")]
+        pub offset: Option<i64>,
+        #[prost(oneof = "append_rows_request::Rows", tags = "4")]
+        pub rows: Option<append_rows_request::Rows>,
+    }
+
+    pub mod append_rows_request {
+        #[derive(Clone, PartialEq, prost::Oneof)]
+        pub enum Rows {
+            #[prost(message, tag = "4")]
+            ProtoRows(super::ProtoData),
+        }
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct Status {
+        #[prost(int32, tag = "1")]
+        pub code: i32,
+        #[prost(string, tag = "2")]
+        pub message: String,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct AppendResult {
+        #[prost(message, optional, tag = "1")]
+        pub offset: Option<i64>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct RowError {
+        #[prost(int64, tag = "1")]
+        pub index: i64,
+        #[prost(int32, tag = "2")]
+        pub code: i32,
+        #[prost(string, tag = "3")]
+        pub message: String,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct AppendRowsResponse {
+        #[prost(oneof = "append_rows_response::Response", tags = "1, 2")]
+        pub response: Option<append_rows_response::Response>,
+        #[prost(message, repeated, tag = "4")]
+        pub row_errors: Vec<RowError>,
+    }
+
+    pub mod append_rows_response {
+        #[derive(Clone, PartialEq, prost::Oneof)]
+        pub enum Response {
+            #[prost(message, tag = "1")]
+            AppendResult(super::AppendResult),
+            #[prost(message, tag = "2")]
+            Error(super::Status),
+        }
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct FinalizeWriteStreamRequest {
+        #[prost(string, tag = "1")]
+        pub name: String,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct FinalizeWriteStreamResponse {
+        #[prost(int64, tag = "1")]
+        pub row_count: i64,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct BatchCommitWriteStreamsRequest {
+        #[prost(string, tag = "1")]
+        pub parent: String,
+        #[prost(string, repeated, tag = "2")]
+        pub write_streams: Vec<String>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct StorageError {
+        #[prost(int32, tag = "1")]
+        pub code: i32,
+        #[prost(string, tag = "2")]
+        pub entity: String,
+        #[prost(string, tag = "3")]
+        pub error_message: String,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct BatchCommitWriteStreamsResponse {
+        #[prost(message, optional, tag = "1")]
+        pub commit_time: Option<Timestamp>,
+        #[prost(message, repeated, tag = "2")]
+        pub stream_errors: Vec<StorageError>,
+    }
+}
+
+/// Type of the write streams of the Storage Write API.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum StreamType {
+    //Rows are visible as soon as they are appended
+    Committed,
+    //Rows are visible once all of them are appended and the stream is committed
+    Pending,
+}
+
+impl FromStr for StreamType {
+    type Err = String;
+
+    fn from_str(stream_type: &str) -> Result<StreamType, String> {
+        match stream_type {
+            "committed" => Ok(StreamType::Committed),
+            "pending" => Ok(StreamType::Pending),
+            _ => Err(format!("Unknown stream type {}", stream_type)),
+        }
+    }
+}
+
+/// Rows of a table encoded for the Storage Write API and how far their
+/// write got, so a retry resumes at the first unacknowledged offset
+/// instead of appending the same rows twice.
+pub struct PendingWrite {
+    table_id: String,
+    descriptor: DescriptorProto,
+    //Serialized rows split in requests below the size limit
+    chunks: Vec<Vec<Vec<u8>>>,
+    stream: Option<String>,
+    written_chunks: usize,
+    //Offset in the stream of the next chunk
+    offset: i64,
+}
+
+impl PendingWrite {
+    pub fn new<T: Serialize + Record>(table_id: &str, rows: &[T]) -> Result<PendingWrite, String> {
+        let fields = T::fields();
+        let mut chunks: Vec<Vec<Vec<u8>>> = Vec::new();
+        let mut chunk: Vec<Vec<u8>> = Vec::new();
+        let mut chunk_bytes = 0;
+        for row in rows {
+            let value = serde_json::to_value(row)
+                .map_err(|err| format!("Failed to serialize row: {}", err))?;
+            let encoded = proto_row::encode(&fields, &value)?;
+            if !chunk.is_empty() && chunk_bytes + encoded.len() > MAX_REQUEST_BYTES {
+                chunks.push(chunk);
+                chunk = Vec::new();
+                chunk_bytes = 0;
+            }
+            chunk_bytes += encoded.len();
+            chunk.push(encoded);
+        }
+        if !chunk.is_empty() {
+            chunks.push(chunk);
+        }
+        return Ok(PendingWrite {
+            table_id: table_id.to_string(),
+            descriptor: proto_row::descriptor(table_id, &fields),
+            chunks: chunks,
+            stream: None,
+            written_chunks: 0,
+            offset: 0,
+        });
+    }
+
+    fn reset(&mut self) {
+        self.stream = None;
+        self.written_chunks = 0;
+        self.offset = 0;
+    }
+}
+
+// Committed stream of a table, reused by every write to the table
+struct TableStream {
+    name: String,
+    //Offset after the last acknowledged row
+    next_offset: i64,
+}
+
+/// Client of the BigQuery Storage Write API.
+/// Committed streams are created once per table and reused by every write.
+/// Pending streams are created for each write, since they are committed whole.
+pub struct StorageWriter {
+    channel: Channel,
+    authenticator: GcpAuthenticator,
+    project_id: String,
+    dataset_id: String,
+    stream_type: StreamType,
+    streams: Mutex<HashMap<String, TableStream>>,
+}
+
+impl StorageWriter {
+    pub async fn new(
+        gcp_key: &str,
+        project_id: &str,
+        dataset_id: &str,
+        stream_type: StreamType,
+    ) -> Result<StorageWriter, String> {
+        let authenticator = gcp_auth::authenticator(gcp_key).await?;
+        let channel = Channel::from_static(ENDPOINT)
+            .tls_config(ClientTlsConfig::new().domain_name(DOMAIN))
+            .map_err(|err| format!("Invalid TLS config: {}", err))?
+            .connect()
+            .await
+            .map_err(|err| format!("Failed to connect to {}: {}", ENDPOINT, err))?;
+        return Ok(StorageWriter {
+            channel: channel,
+            authenticator: authenticator,
+            project_id: project_id.to_string(),
+            dataset_id: dataset_id.to_string(),
+            stream_type: stream_type,
+            streams: Mutex::new(HashMap::new()),
+        });
+    }
+
+    fn table_path(&self, table_id: &str) -> String {
+        format!("projects/{}/datasets/{}/tables/{}", self.project_id, self.dataset_id, table_id)
+    }
+
+    // Attach the access token and the routing header of the resource
+    async fn request<M>(&self, message: M, routing_key: &str, resource: &str) -> Result<Request<M>, String> {
+        let token = gcp_auth::access_token(&self.authenticator).await?;
+        let authorization = MetadataValue::from_str(&format!("Bearer {}", token))
+            .map_err(|err| format!("Invalid access token: {}", err))?;
+        let routing = MetadataValue::from_str(
+                &format!("{}={}", routing_key, resource.replace('/', "%2F")))
+            .map_err(|err| format!("Invalid resource name {}: {}", resource, err))?;
+        let mut request = Request::new(message);
+        request.metadata_mut().insert("authorization", authorization);
+        request.metadata_mut().insert("x-goog-request-params", routing);
+        return Ok(request);
+    }
+
+    async fn grpc(&self) -> Result<Grpc<Channel>, String> {
+        let mut grpc = Grpc::new(self.channel.clone());
+        grpc.ready()
+            .await
+            .map_err(|err| format!("Storage Write API is not ready: {}", err))?;
+        return Ok(grpc);
+    }
+
+    async fn unary<Req, Res>(&self, method: &'static str, request: Request<Req>) -> Result<Res, String>
+    where
+        Req: prost::Message + Send + Sync + 'static,
+        Res: prost::Message + Default + Send + Sync + 'static,
+    {
+        let response = self.grpc()
+            .await?
+            .unary(request, PathAndQuery::from_static(method), ProstCodec::default())
+            .await
+            .map_err(|status| format!("{} failed: {}", method, status))?;
+        return Ok(response.into_inner());
+    }
+
+    async fn create_stream(&self, table_id: &str) -> Result<String, String> {
+        let parent = self.table_path(table_id);
+        let stream_type = match self.stream_type {
+            StreamType::Committed => WriteStreamType::Committed,
+            StreamType::Pending => WriteStreamType::Pending,
+        };
+        let message = CreateWriteStreamRequest {
+            parent: parent.clone(),
+            write_stream: Some(WriteStream {
+                name: String::new(),
+                r#type: stream_type as i32,
+            }),
+        };
+        let request = self.request(message, "parent", &parent).await?;
+        let stream: WriteStream = self.unary(CREATE_WRITE_STREAM, request).await?;
+        return Ok(stream.name);
+    }
+
+    // Committed stream of the table and its next offset, created on first use
+    async fn table_stream(&self, table_id: &str) -> Result<(String, i64), String> {
+        if let Some(stream) = self.streams.lock().unwrap().get(table_id) {
+            return Ok((stream.name.clone(), stream.next_offset));
+        }
+        let name = self.create_stream(table_id).await?;
+        self.streams.lock().unwrap().insert(table_id.to_string(), TableStream {
+            name: name.clone(),
+            next_offset: 0,
+        });
+        return Ok((name, 0));
+    }
+
+    fn set_next_offset(&self, table_id: &str, stream_name: &str, next_offset: i64) {
+        if let Some(stream) = self.streams.lock().unwrap().get_mut(table_id) {
+            if stream.name == stream_name {
+                stream.next_offset = next_offset;
+            }
+        }
+    }
+
+    /// Create a new stream for the next write to the table, once a write
+    /// gave up on the current one.
+    pub fn forget_stream(&self, table_id: &str) {
+        self.streams.lock().unwrap().remove(table_id);
+    }
+
+    // Append the chunks that were not acknowledged yet, each at its offset
+    async fn append(&self, stream_name: &str, write: &mut PendingWrite) -> Result<(), String> {
+        let mut offset = write.offset;
+        let mut messages: Vec<AppendRowsRequest> = Vec::new();
+        for rows in &write.chunks[write.written_chunks..] {
+            //The schema is only needed by the first request of the connection
+            let mut writer_schema: Option<ProtoSchema> = None;
+            if messages.is_empty() {
+                writer_schema = Some(ProtoSchema {
+                    proto_descriptor: Some(write.descriptor.clone()),
+                });
+            }
+            messages.push(AppendRowsRequest {
+                write_stream: stream_name.to_string(),
+                offset: Some(offset),
+                rows: Some(append_rows_request::Rows::ProtoRows(ProtoData {
+                    writer_schema: writer_schema,
+                    rows: Some(ProtoRows {
+                        serialized_rows: rows.clone(),
+                    }),
+                })),
+            });
+            offset += rows.len() as i64;
+        }
+
+        let message_count = messages.len();
+        let request = self.request(stream::iter(messages), "write_stream", stream_name).await?;
+        let mut responses = self.grpc()
+            .await?
+            .streaming(request, PathAndQuery::from_static(APPEND_ROWS), ProstCodec::default())
+            .await
+            .map_err(|status| format!("AppendRows failed: {}", status))?
+            .into_inner();
+        for _ in 0..message_count {
+            let response: AppendRowsResponse = responses
+                .message()
+                .await
+                .map_err(|status| format!("AppendRows failed: {}", status))?
+                .ok_or_else(|| "AppendRows closed before all rows were acknowledged".to_string())?;
+            if !response.row_errors.is_empty() {
+                return Err(format!("Rows were rejected: {:?}", response.row_errors));
+            }
+            match response.response {
+                Some(append_rows_response::Response::AppendResult(_)) => {}
+                Some(append_rows_response::Response::Error(status)) => {
+                    //Written by an attempt whose response was lost
+                    if status.code != ALREADY_EXISTS {
+                        return Err(format!("AppendRows failed: {}", status.message));
+                    }
+                }
+                None => {
+                    return Err("AppendRows returned an empty response".to_string());
+                }
+            }
+            write.offset += write.chunks[write.written_chunks].len() as i64;
+            write.written_chunks += 1;
+        }
+        return Ok(());
+    }
+
+    async fn commit(&self, table_id: &str, stream_name: &str) -> Result<(), String> {
+        let message = FinalizeWriteStreamRequest {
+            name: stream_name.to_string(),
+        };
+        let request = self.request(message, "name", stream_name).await?;
+        let _: FinalizeWriteStreamResponse = self.unary(FINALIZE_WRITE_STREAM, request).await?;
+
+        let parent = self.table_path(table_id);
+        let message = BatchCommitWriteStreamsRequest {
+            parent: parent.clone(),
+            write_streams: vec![stream_name.to_string()],
+        };
+        let request = self.request(message, "parent", &parent).await?;
+        let response: BatchCommitWriteStreamsResponse =
+            self.unary(BATCH_COMMIT_WRITE_STREAMS, request).await?;
+        if !response.stream_errors.is_empty() || response.commit_time.is_none() {
+            return Err(format!("Failed to commit stream: {:?}", response.stream_errors));
+        }
+        return Ok(());
+    }
+
+    /// Write the rows that are left. Can be called again after an error.
+    pub async fn write(&self, write: &mut PendingWrite) -> Result<(), String> {
+        let stream_name = match &write.stream {
+            Some(name) => name.clone(),
+            None => {
+                let (name, offset) = match self.stream_type {
+                    StreamType::Committed => self.table_stream(&write.table_id).await?,
+                    StreamType::Pending => (self.create_stream(&write.table_id).await?, 0),
+                };
+                write.stream = Some(name.clone());
+                write.offset = offset;
+                name
+            }
+        };
+        if write.written_chunks < write.chunks.len() {
+            let res = self.append(&stream_name, write).await;
+            //Acknowledged rows stay in the stream even if the write gives up
+            if self.stream_type == StreamType::Committed {
+                self.set_next_offset(&write.table_id, &stream_name, write.offset);
+            }
+            res?;
+        }
+        if self.stream_type == StreamType::Pending {
+            if let Err(err) = self.commit(&write.table_id, &stream_name).await {
+                //Rows of an uncommitted stream are dropped with it.
+                //Write all of them again to a new stream.
+                write.reset();
+                return Err(err);
+            }
+        }
+        return Ok(());
+    }
+}
@@ -7,72 +7,86 @@ use solana_sdk::{
 };
 use solana_transaction_status::UiTransactionStatusMeta;
 
-#[derive(Serialize)]
-pub struct Transaction {
-    block_timestamp: Option<DateTime<Utc>>,
-    slot: u64,
-    transaction_id: String,
-    is_successful: bool,
-    error: String,
-    fee: u64,
-    accounts: Vec<Account>,
-    instructions: Vec<Instruction>,
-    log_messages: Vec<String>,
-}
+use crate::{
+    amount,
+    bigquery_record,
+    compute_budget::ComputeBudget,
+    log_message::{
+        self,
+        Invocation,
+    },
+    schema::{
+        Base64,
+        Numeric,
+    },
+};
 
-#[derive(Serialize)]
-struct Account {
-    address: String,
-    pre_sol_balance: u64,
-    post_sol_balance: u64,
-    pre_token_balances: Vec<TokenBalance>,
-    post_token_balances: Vec<TokenBalance>,
+bigquery_record! {
+    #[derive(Serialize)]
+    pub struct Transaction {
+        block_timestamp: Option<DateTime<Utc>>,
+        slot: u64,
+        transaction_id: String,
+        is_successful: bool,
+        error: String,
+        fee: u64,
+        base_fee: u64,
+        priority_fee: u64,
+        //Price per compute unit in micro-lamports
+        compute_unit_price: u64,
+        compute_unit_limit: u64,
+        compute_units_consumed: Option<u64>,
+        accounts: Vec<Account>,
+        instructions: Vec<Instruction>,
+        log_messages: Vec<String>,
+    }
 }
 
-#[derive(Serialize)]
-struct TokenBalance {
-    mint: String,
-    amount: String,
-}
 
-#[derive(Serialize)]
-struct Instruction {
-    program_id: String,
-    accounts: Vec<String>,
-    //Base64 encoded data buffer
-    data: String,
+bigquery_record! {
+    #[derive(Serialize)]
+    struct Account {
+        address: String,
+        pre_sol_balance: u64,
+        post_sol_balance: u64,
+        pre_token_balances: Vec<TokenBalance>,
+        post_token_balances: Vec<TokenBalance>,
+    }
 }
 
-impl Transaction {
-    // trim amount to max decimal precision
-    // allows in a BigQuery Decimal type
-    fn trim_decimals(amount: &String) -> &str {
-        const MAX_BQ_DECIMALS: usize = 9;
-        let parts: Vec<&str> = amount.split('.').collect();
-
-        //any decimals at all?
-        if parts.len() < 2 {
-            return &amount[..];
-        }
 
-        //less than the max decimals?
-        let decimals = parts[1].len();
-        if decimals <= MAX_BQ_DECIMALS {
-            return &amount[..];
-        }
+bigquery_record! {
+    #[derive(Serialize)]
+    struct TokenBalance {
+        mint: String,
+        amount: Numeric,
+    }
+}
 
-        //return the amount with the
-        //the full integer part, the period, and the max decimals
-        let max_length = parts[0].len() + 1 + MAX_BQ_DECIMALS;
-        return &amount[..max_length];
+
+bigquery_record! {
+    #[derive(Serialize)]
+    struct Instruction {
+        program_id: String,
+        accounts: Vec<String>,
+        //Base64 encoded data buffer
+        data: Base64,
+        invocations: Vec<Invocation>,
     }
+}
+
 
+impl Transaction {
+    /// Row of the transaction, the invocations are parsed from the log
+    /// messages of its meta.
     pub fn new(
         block_timestamp: &Option<DateTime<Utc>>,
         slot: Slot,
         meta: &UiTransactionStatusMeta,
         solana_transaction: &SolanaTransaction,
+        invocations: Vec<Vec<Invocation>>,
     ) -> Transaction {
+        let compute_budget = ComputeBudget::new(solana_transaction);
         let mut transaction = Transaction {
             block_timestamp: *block_timestamp,
             slot: slot,
@@ -80,6 +94,11 @@ impl Transaction {
             is_successful: false,
             error: String::from(""),
             fee: meta.fee,
+            base_fee: meta.fee.saturating_sub(compute_budget.priority_fee),
+            priority_fee: compute_budget.priority_fee,
+            compute_unit_price: compute_budget.compute_unit_price,
+            compute_unit_limit: compute_budget.compute_unit_limit,
+            compute_units_consumed: None,
             accounts: Vec::new(),
             instructions: Vec::new(),
             log_messages: Vec::new(),
@@ -110,7 +129,8 @@ impl Transaction {
             transaction.instructions.push(Instruction {
                 program_id: transaction.accounts[instruction.program_id_index as usize].address.clone(),
                 accounts: accounts,
-                data: base64::encode(&instruction.data[..]),
+                data: Base64(base64::encode(&instruction.data[..])),
+                invocations: Vec::new(),
             });
         }
 
@@ -118,7 +138,8 @@ impl Transaction {
             for balance in balances {
                 let token_balance = TokenBalance {
                     mint: balance.mint.clone(),
-                    amount: Self::trim_decimals(&balance.ui_token_amount.ui_amount_string).to_owned(),
+                    amount: Numeric(
+                        amount::trim_decimals(&balance.ui_token_amount.ui_amount_string).to_owned()),
                 };
                 transaction.accounts[balance.account_index as usize]
                     .pre_token_balances.push(token_balance);
@@ -128,7 +149,8 @@ impl Transaction {
             for balance in balances {
                 let token_balance = TokenBalance {
                     mint: balance.mint.clone(),
-                    amount: Self::trim_decimals(&balance.ui_token_amount.ui_amount_string).to_owned(),
+                    amount: Numeric(
+                        amount::trim_decimals(&balance.ui_token_amount.ui_amount_string).to_owned()),
                 };
                 transaction.accounts[balance.account_index as usize]
                     .post_token_balances.push(token_balance);
@@ -143,6 +165,27 @@ impl Transaction {
             }
         }
 
+        //Precompiled programs (e.g. Ed25519) do not log an invocation, so
+        //each group belongs to the next instruction of the invoked program
+        let mut groups = invocations.into_iter().peekable();
+        for instruction in transaction.instructions.iter_mut() {
+            let is_invoked = match groups.peek().and_then(|group| group.first()) {
+                Some(invocation) => invocation.program_id == instruction.program_id,
+                None => false,
+            };
+            if is_invoked {
+                instruction.invocations = groups.next().unwrap();
+            }
+        }
+
+        //Nodes report computeUnitsConsumed in the transaction meta, but the
+        //meta of the pinned client does not have the field, so add up what
+        //each top level invocation logged.
+        transaction.compute_units_consumed = log_message::compute_units_consumed(
+            transaction.instructions
+                .iter()
+                .flat_map(|instruction| instruction.invocations.iter()));
+
         return transaction;
     }
 }
@@ -0,0 +1,259 @@
+use std::collections::HashMap;
+
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use serde_json::Value;
+use solana_sdk::{
+    clock::Slot,
+    instruction::CompiledInstruction,
+    transaction::Transaction as SolanaTransaction,
+};
+use solana_transaction_status::{
+    parse_instruction::parse,
+    UiInstruction,
+    UiTransactionStatusMeta,
+};
+
+use crate::{
+    amount::{
+        self,
+        SOL_DECIMALS,
+    },
+    bigquery_record,
+    schema::Numeric,
+};
+
+const SYSTEM_PROGRAM: &str = "system";
+const TOKEN_PROGRAM: &str = "spl-token";
+
+bigquery_record! {
+    /// A SOL or SPL token movement derived from a System
+    /// or Token program instruction.
+    #[derive(Serialize)]
+    pub struct Transfer {
+        block_timestamp: Option<DateTime<Utc>>,
+        slot: u64,
+        transaction_id: String,
+        instruction_index: u64,
+        //Only set for inner instructions
+        inner_instruction_index: Option<u64>,
+        program_id: String,
+        transfer_type: String,
+        //None when tokens are minted
+        source: Option<String>,
+        //None when tokens are burned
+        destination: Option<String>,
+        //None for SOL transfers
+        mint: Option<String>,
+        amount: Numeric,
+    }
+}
+
+
+//Mint and decimals of each token account, by address
+type TokenAccounts = HashMap<String, (String, u8)>;
+
+fn token_accounts(
+    meta: &UiTransactionStatusMeta,
+    solana_transaction: &SolanaTransaction,
+) -> TokenAccounts {
+    let mut accounts = TokenAccounts::new();
+    let account_keys = &solana_transaction.message.account_keys;
+    let balances = meta.pre_token_balances.iter()
+        .chain(meta.post_token_balances.iter())
+        .flatten();
+    for balance in balances {
+        accounts.insert(
+            account_keys[balance.account_index as usize].to_string(),
+            (balance.mint.clone(), balance.ui_token_amount.decimals),
+        );
+    }
+    return accounts;
+}
+
+fn get_string(info: &Value, key: &str) -> Option<String> {
+    return info[key].as_str().map(|s| s.to_string());
+}
+
+fn get_raw_amount(info: &Value, key: &str) -> Option<i128> {
+    match &info[key] {
+        Value::Number(number) => number.as_u64().map(|n| n as i128),
+        Value::String(string) => string.parse().ok(),
+        _ => None,
+    }
+}
+
+impl Transfer {
+    fn new_sol_transfer(transfer_type: &str, info: &Value) -> Option<Transfer> {
+        let source = get_string(info, "source")?;
+        let destination = get_string(info, "destination")
+            .or(get_string(info, "newAccount"))?;
+        let lamports = get_raw_amount(info, "lamports")?;
+        return Some(Transfer {
+            block_timestamp: None,
+            slot: 0,
+            transaction_id: String::new(),
+            instruction_index: 0,
+            inner_instruction_index: None,
+            program_id: String::new(),
+            transfer_type: transfer_type.to_string(),
+            source: Some(source),
+            destination: Some(destination),
+            mint: None,
+            amount: Numeric(amount::format_amount(lamports, SOL_DECIMALS)),
+        });
+    }
+
+    fn new_token_transfer(
+        transfer_type: &str,
+        info: &Value,
+        token_accounts: &TokenAccounts,
+    ) -> Option<Transfer> {
+        let source: Option<String>;
+        let destination: Option<String>;
+        match transfer_type {
+            "transfer" | "transferChecked" => {
+                source = Some(get_string(info, "source")?);
+                destination = Some(get_string(info, "destination")?);
+            }
+            "mintTo" | "mintToChecked" => {
+                source = None;
+                destination = Some(get_string(info, "account")?);
+            }
+            "burn" | "burnChecked" => {
+                source = Some(get_string(info, "account")?);
+                destination = None;
+            }
+            _ => {
+                return None;
+            }
+        }
+
+        //Plain instructions carry neither the mint nor the decimals,
+        //so look them up in the token balances of the transaction.
+        let token_account = source.as_ref().or(destination.as_ref())?;
+        let known_account = token_accounts.get(token_account);
+        let raw_amount: i128;
+        let decimals: u8;
+        let mint: String;
+        if info["tokenAmount"].is_object() {
+            raw_amount = get_raw_amount(&info["tokenAmount"], "amount")?;
+            decimals = info["tokenAmount"]["decimals"].as_u64()? as u8;
+            mint = get_string(info, "mint")
+                .or(known_account.map(|(mint, _)| mint.clone()))?;
+        } else {
+            raw_amount = get_raw_amount(info, "amount")?;
+            let (account_mint, account_decimals) = known_account?;
+            decimals = *account_decimals;
+            mint = get_string(info, "mint").unwrap_or(account_mint.clone());
+        }
+
+        return Some(Transfer {
+            block_timestamp: None,
+            slot: 0,
+            transaction_id: String::new(),
+            instruction_index: 0,
+            inner_instruction_index: None,
+            program_id: String::new(),
+            transfer_type: transfer_type.to_string(),
+            source: source,
+            destination: destination,
+            mint: Some(mint),
+            amount: Numeric(amount::format_amount(raw_amount, decimals)),
+        });
+    }
+
+    fn from_instruction(
+        instruction: &CompiledInstruction,
+        solana_transaction: &SolanaTransaction,
+        token_accounts: &TokenAccounts,
+    ) -> Option<Transfer> {
+        let account_keys = &solana_transaction.message.account_keys;
+        let program_id = account_keys.get(instruction.program_id_index as usize)?;
+        let parsed = parse(program_id, instruction, account_keys).ok()?;
+        let transfer_type = parsed.parsed["type"].as_str()?;
+        let info = &parsed.parsed["info"];
+
+        let mut transfer: Transfer;
+        match parsed.program.as_str() {
+            SYSTEM_PROGRAM => {
+                match transfer_type {
+                    "transfer"
+                    | "transferWithSeed"
+                    | "createAccount"
+                    | "createAccountWithSeed" => {
+                        transfer = Self::new_sol_transfer(transfer_type, info)?;
+                    }
+                    _ => {
+                        return None;
+                    }
+                }
+            }
+            TOKEN_PROGRAM => {
+                transfer = Self::new_token_transfer(transfer_type, info, token_accounts)?;
+            }
+            _ => {
+                return None;
+            }
+        }
+        transfer.program_id = parsed.program_id;
+        return Some(transfer);
+    }
+
+    /// Derive all SOL and token transfers made by a transaction,
+    /// including the ones made by inner instructions.
+    pub fn from_transaction(
+        block_timestamp: &Option<DateTime<Utc>>,
+        slot: Slot,
+        meta: &UiTransactionStatusMeta,
+        solana_transaction: &SolanaTransaction,
+    ) -> Vec<Transfer> {
+        //Nothing moved if the transaction failed
+        if meta.status.is_err() {
+            return Vec::new();
+        }
+        let token_accounts = token_accounts(meta, solana_transaction);
+
+        //(instruction index, inner instruction index, instruction)
+        let mut instructions: Vec<(u64, Option<u64>, CompiledInstruction)> = Vec::new();
+        for (index, instruction) in solana_transaction.message.instructions.iter().enumerate() {
+            instructions.push((index as u64, None, instruction.clone()));
+        }
+        if let Some(inner_instructions) = &meta.inner_instructions {
+            for inner in inner_instructions {
+                for (inner_index, instruction) in inner.instructions.iter().enumerate() {
+                    if let UiInstruction::Compiled(compiled) = instruction {
+                        let data = match bs58::decode(&compiled.data).into_vec() {
+                            Ok(data) => data,
+                            Err(_) => continue,
+                        };
+                        instructions.push((
+                            inner.index as u64,
+                            Some(inner_index as u64),
+                            CompiledInstruction {
+                                program_id_index: compiled.program_id_index,
+                                accounts: compiled.accounts.clone(),
+                                data: data,
+                            },
+                        ));
+                    }
+                }
+            }
+        }
+        instructions.sort_by_key(|(index, inner_index, _)| (*index, *inner_index));
+
+        let mut transfers: Vec<Transfer> = Vec::new();
+        for (index, inner_index, instruction) in instructions {
+            if let Some(mut transfer) = Self::from_instruction(
+                &instruction, solana_transaction, &token_accounts) {
+                transfer.block_timestamp = *block_timestamp;
+                transfer.slot = slot;
+                transfer.transaction_id = solana_transaction.signatures[0].to_string();
+                transfer.instruction_index = index;
+                transfer.inner_instruction_index = inner_index;
+                transfers.push(transfer);
+            }
+        }
+        return transfers;
+    }
+}
@@ -0,0 +1,164 @@
+use std::str::FromStr;
+
+use chrono::{
+    DateTime,
+    Utc,
+};
+use serde::{
+    Deserialize,
+    Serialize,
+};
+use serde_json::Value;
+use solana_sdk::{
+    clock::Slot,
+    transaction::Transaction as SolanaTransaction,
+};
+use solana_transaction_status::{
+    parse_instruction::parse,
+    UiTransactionStatusMeta,
+};
+
+use crate::{
+    bigquery_record,
+    block,
+};
+
+const VOTE_PROGRAM_ID: &str = "Vote111111111111111111111111111111111111111";
+//Parsed types of the Vote program instructions that cast a vote. The other
+//instructions, e.g. withdraw or authorize, are recorded as transactions
+const VOTE_INSTRUCTION_TYPES: [&str; 6] = [
+    "vote",
+    "voteSwitch",
+    "updatevotestate",
+    "updatevotestateswitch",
+    "compactupdatevotestate",
+    "compactupdatevotestateswitch",
+];
+
+/// How vote transactions are ingested.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum VoteMode {
+    //Record votes in the transactions table like any other transaction
+    Include,
+    //Do not record votes at all
+    Skip,
+    //Record votes in the compact votes table
+    Separate,
+}
+
+impl FromStr for VoteMode {
+    type Err = String;
+
+    fn from_str(mode: &str) -> Result<VoteMode, String> {
+        match mode {
+            "include" => Ok(VoteMode::Include),
+            "skip" => Ok(VoteMode::Skip),
+            "separate" => Ok(VoteMode::Separate),
+            _ => Err(format!("Unknown vote mode {}", mode)),
+        }
+    }
+}
+
+bigquery_record! {
+    /// A validator vote, recorded instead of the full vote transaction.
+    #[derive(Serialize)]
+    pub struct Vote {
+        block_timestamp: Option<DateTime<Utc>>,
+        slot: u64,
+        transaction_id: String,
+        is_successful: bool,
+        validator: String,
+        vote_account: String,
+        voted_slots: Vec<u64>,
+        vote_hash: String,
+        vote_timestamp: Option<DateTime<Utc>>,
+    }
+}
+
+// Info of the first instruction of the transaction that casts a vote
+fn parse_vote_instruction(solana_transaction: &SolanaTransaction) -> Option<Value> {
+    let account_keys = &solana_transaction.message.account_keys;
+    for instruction in &solana_transaction.message.instructions {
+        let program_id = &account_keys[instruction.program_id_index as usize];
+        if program_id.to_string() != VOTE_PROGRAM_ID {
+            continue;
+        }
+        let parsed = match parse(program_id, instruction, account_keys) {
+            Ok(parsed) => parsed,
+            Err(_) => continue,
+        };
+        let instruction_type = parsed.parsed["type"].as_str().unwrap_or_default();
+        if VOTE_INSTRUCTION_TYPES.contains(&instruction_type) {
+            return Some(parsed.parsed["info"].clone());
+        }
+    }
+    return None;
+}
+
+/// Whether the transaction casts a vote. Other Vote program transactions
+/// are not votes.
+pub fn is_vote_transaction(solana_transaction: &SolanaTransaction) -> bool {
+    return parse_vote_instruction(solana_transaction).is_some();
+}
+
+impl Vote {
+    /// Build a vote from the first vote instruction of a transaction.
+    /// None if the transaction does not cast a vote.
+    pub fn from_transaction(
+        block_timestamp: &Option<DateTime<Utc>>,
+        slot: Slot,
+        meta: &UiTransactionStatusMeta,
+        solana_transaction: &SolanaTransaction,
+    ) -> Option<Vote> {
+        let account_keys = &solana_transaction.message.account_keys;
+        let info = parse_vote_instruction(solana_transaction)?;
+        let voted_slots: Vec<u64>;
+        let vote: &Value;
+        if info["vote"].is_object() {
+            vote = &info["vote"];
+            voted_slots = vote["slots"]
+                .as_array()
+                .map(|slots| slots.iter().filter_map(|s| s.as_u64()).collect())
+                .unwrap_or_default();
+        } else {
+            //Vote state updates list the lockouts of the voted slots
+            vote = &info["voteStateUpdate"];
+            voted_slots = vote["lockouts"]
+                .as_array()
+                .map(|lockouts| lockouts.iter().filter_map(|l| l["slot"].as_u64()).collect())
+                .unwrap_or_default();
+        }
+        let vote_timestamp: Option<DateTime<Utc>>;
+        match vote["timestamp"].as_i64() {
+            None => {
+                vote_timestamp = None;
+            }
+            Some(ts) => {
+                //Out of range timestamps are not recorded
+                vote_timestamp = block::timestamp(ts);
+            }
+        }
+
+        return Some(Vote {
+            block_timestamp: *block_timestamp,
+            slot: slot,
+            transaction_id: solana_transaction.signatures[0].to_string(),
+            is_successful: meta.status.is_ok(),
+            validator: info["voteAuthority"]
+                .as_str()
+                .map(|s| s.to_string())
+                .unwrap_or(account_keys[0].to_string()),
+            vote_account: info["voteAccount"]
+                .as_str()
+                .unwrap_or_default()
+                .to_string(),
+            voted_slots: voted_slots,
+            vote_hash: vote["hash"]
+                .as_str()
+                .unwrap_or_default()
+                .to_string(),
+            vote_timestamp: vote_timestamp,
+        });
+    }
+}